@@ -0,0 +1,252 @@
+use std::collections::{BTreeSet, HashMap};
+
+use bincode::{Decode, Encode, config};
+
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    storage::{Storage, WriteBatch},
+    tx::{SpentOutput, TxOutput},
+};
+
+// pub_key_hash:高度:tx_id -> AddressTx
+const ADDR_PREFIX: &str = "addr-";
+// 存在时表示地址索引已开启且完整
+const ADDR_INDEX_KEY: &str = "addrindex";
+
+/// 某地址参与的一笔主链交易
+#[derive(Debug, Encode, Decode, Clone)]
+pub struct AddressTx {
+    pub tx_id: Vec<u8>,
+    pub block_hash: String,
+    pub height: u128,
+    // 该地址收到的金额
+    pub received: u128,
+    // 该地址花费的Output金额, 包含找零
+    pub sent: u128,
+    pub coinbase: bool,
+    // 对方的pub_key_hash: 收款时为付款方, 付款时为收款方; coinbase没有付款方
+    pub counterparties: Vec<Vec<u8>>,
+}
+
+/// 地址 -> 交易历史的索引, 与主链在同一个batch中维护
+pub struct AddressIndex;
+
+impl AddressIndex {
+    fn key(pub_key_hash: &[u8], height: u128, tx_id: &[u8]) -> String {
+        format!(
+            "{}{}:{:020}:{}",
+            ADDR_PREFIX,
+            hex::encode(pub_key_hash),
+            height,
+            hex::encode(tx_id)
+        )
+    }
+
+    pub fn enabled(database: &dyn Storage) -> bool {
+        database
+            .contains_key(ADDR_INDEX_KEY.as_bytes())
+            .expect("Failed to read address index flag")
+    }
+
+    /// 计算区块中每笔交易涉及的地址记录
+    ///
+    /// # Arguments
+    ///
+    /// - `block` (`&Block`) - 区块
+    /// - `spent` (`&HashMap<(Vec<u8>, usize), TxOutput>`) - 区块中input引用的Output
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<(String, AddressTx)>` - (索引key, 记录)
+    fn entries(
+        block: &Block,
+        spent: &HashMap<(Vec<u8>, usize), TxOutput>,
+    ) -> Vec<(String, AddressTx)> {
+        let mut entries = vec![];
+
+        for tx in &block.transactions {
+            // pub_key_hash -> (received, sent)
+            let mut amounts = HashMap::<Vec<u8>, (u128, u128)>::new();
+            let mut payers = BTreeSet::<Vec<u8>>::new();
+            let mut payees = BTreeSet::<Vec<u8>>::new();
+
+            for input in tx.inputs.iter().filter(|_| !tx.is_coinbase()) {
+                // 校验通过的区块不会引用不存在的Output
+                let Some(output) = spent.get(&(input.tx_id.clone(), input.out_idx)) else {
+                    continue;
+                };
+                amounts.entry(output.pub_key_hash.clone()).or_default().1 += output.amount;
+                payers.insert(output.pub_key_hash.clone());
+            }
+            for output in &tx.outputs {
+                amounts.entry(output.pub_key_hash.clone()).or_default().0 += output.amount;
+                payees.insert(output.pub_key_hash.clone());
+            }
+
+            for (pub_key_hash, (received, sent)) in amounts {
+                let others = if sent > 0 { &payees } else { &payers };
+                let counterparties = others
+                    .iter()
+                    .filter(|other| **other != pub_key_hash)
+                    .cloned()
+                    .collect();
+
+                let record = AddressTx {
+                    tx_id: tx.id.clone(),
+                    block_hash: block.hash.clone(),
+                    height: block.height,
+                    received,
+                    sent,
+                    coinbase: tx.is_coinbase(),
+                    counterparties,
+                };
+                entries.push((AddressIndex::key(&pub_key_hash, block.height, &tx.id), record));
+            }
+        }
+
+        entries
+    }
+
+    /// 区块接入主链时写入地址记录
+    ///
+    /// # Arguments
+    ///
+    /// - `block` (`&Block`) - 接入的区块
+    /// - `spent` (`&[SpentOutput]`) - 该区块花费的所有Output
+    /// - `batch` (`&mut WriteBatch`) - 待提交的batch
+    pub fn connect_block(block: &Block, spent: &[SpentOutput], batch: &mut WriteBatch) {
+        let spent = spent
+            .iter()
+            .map(|spent| {
+                (
+                    (spent.tx_id.clone(), spent.out_idx),
+                    spent.utxo.output.clone(),
+                )
+            })
+            .collect();
+
+        for (key, record) in AddressIndex::entries(block, &spent) {
+            let bytes = bincode::encode_to_vec(record, config::standard()).unwrap();
+            batch.put(key, bytes);
+        }
+    }
+
+    /// 区块从主链回滚时删除地址记录
+    ///
+    /// # Arguments
+    ///
+    /// - `block` (`&Block`) - 回滚的区块
+    /// - `restored` (`&[SpentOutput]`) - 回滚时恢复的Output
+    /// - `batch` (`&mut WriteBatch`) - 待提交的batch
+    pub fn disconnect_block(block: &Block, restored: &[SpentOutput], batch: &mut WriteBatch) {
+        // 区块内产生又被花费的Output不在undo数据中
+        let mut spent: HashMap<(Vec<u8>, usize), TxOutput> = restored
+            .iter()
+            .map(|spent| {
+                (
+                    (spent.tx_id.clone(), spent.out_idx),
+                    spent.utxo.output.clone(),
+                )
+            })
+            .collect();
+        for tx in &block.transactions {
+            for (out_idx, output) in tx.outputs.iter().enumerate() {
+                spent.insert((tx.id.clone(), out_idx), output.clone());
+            }
+        }
+
+        for (key, _) in AddressIndex::entries(block, &spent) {
+            batch.delete(key);
+        }
+    }
+
+    /// 开启或关闭地址索引
+    ///
+    /// 开启时从创世区块开始重放主链补建索引, 关闭时删除已有索引
+    ///
+    /// # Arguments
+    ///
+    /// - `blockchain` (`&Blockchain`) - 主链
+    /// - `enabled` (`bool`) - 是否开启
+    pub async fn set_enabled(blockchain: &Blockchain, enabled: bool) {
+        let mut batch = WriteBatch::default();
+        {
+            let database = blockchain.database.read().await;
+            for result in database.scan_prefix(ADDR_PREFIX.as_bytes()) {
+                let (key, _) = result.unwrap();
+                batch.delete(key);
+            }
+        }
+
+        if enabled {
+            let mut blocks = vec![];
+            let mut iter = blockchain.iterator().await;
+            while let Some(block) = iter.next().await {
+                blocks.push(block);
+            }
+
+            // 重放过程中所有未花费的Output
+            let mut outputs = HashMap::<(Vec<u8>, usize), TxOutput>::new();
+            for block in blocks.iter().rev() {
+                let mut spent = HashMap::new();
+                for tx in &block.transactions {
+                    for input in tx.inputs.iter().filter(|_| !tx.is_coinbase()) {
+                        let outpoint = (input.tx_id.clone(), input.out_idx);
+                        if let Some(output) = outputs.remove(&outpoint) {
+                            spent.insert(outpoint, output);
+                        }
+                    }
+                    for (out_idx, output) in tx.outputs.iter().enumerate() {
+                        outputs.insert((tx.id.clone(), out_idx), output.clone());
+                    }
+                }
+
+                for (key, record) in AddressIndex::entries(block, &spent) {
+                    let bytes = bincode::encode_to_vec(record, config::standard()).unwrap();
+                    batch.put(key, bytes);
+                }
+            }
+            batch.put(ADDR_INDEX_KEY, vec![]);
+        } else {
+            batch.delete(ADDR_INDEX_KEY);
+        }
+
+        blockchain
+            .database
+            .write()
+            .await
+            .apply_batch(batch)
+            .expect("Failed to update address index");
+    }
+
+    /// 查询地址的交易历史, 按高度从低到高
+    ///
+    /// # Arguments
+    ///
+    /// - `blockchain` (`&Blockchain`) - 主链
+    /// - `pub_key_hash` (`&[u8]`) - address
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Vec<AddressTx>>` - 未开启地址索引时为None
+    pub async fn history(blockchain: &Blockchain, pub_key_hash: &[u8]) -> Option<Vec<AddressTx>> {
+        let database = blockchain.database.read().await;
+        if !AddressIndex::enabled(&*database) {
+            return None;
+        }
+
+        let prefix = format!("{}{}:", ADDR_PREFIX, hex::encode(pub_key_hash));
+        let history = database
+            .scan_prefix(prefix.as_bytes())
+            .map(|result| {
+                let (_, val) = result.unwrap();
+                let (record, _): (AddressTx, usize) =
+                    bincode::decode_from_slice(&val, config::standard()).unwrap();
+                record
+            })
+            .collect();
+
+        Some(history)
+    }
+}
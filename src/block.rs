@@ -1,63 +1,148 @@
-use core::panic;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    sync::atomic::AtomicBool,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use bincode::{config, Decode, Encode};
 
-use crate::{merkle::MerkleTree, proof_of_work::ProofOfWork, transaction::Transaction};
+use crate::{
+    merkle::MerkleTree,
+    proof_of_work::{INITIAL_DIFFICULTY, ProofOfWork},
+    transaction::Transaction,
+};
 
-#[derive(Debug, Encode, Decode)]
-pub struct Block {
+pub const BLOCK_VERSION: u32 = 1;
+// 区块编码后的字节数上限, 共识规则
+pub const MAX_BLOCK_SIZE: usize = 1024 * 1024;
+
+/// 区块头, 工作量证明只针对区块头计算
+#[derive(Debug, Encode, Decode, Clone)]
+pub struct BlockHeader {
+    pub version: u32,
     pub prev_hash: String,
+    pub merkle_root: String,
+    pub timestamp: u128,
+    // 前导零bit数, 由链上规则决定
+    pub bits: u8,
+    pub nonce: u32,
+}
+
+/// 已知hash与高度的区块头, 用于在下载区块体之前校验区块头链
+#[derive(Debug, Encode, Decode, Clone)]
+pub struct HeaderEntry {
+    pub header: BlockHeader,
+    pub hash: String,
+    pub height: u128,
+}
+
+#[derive(Debug, Encode, Decode, Clone)]
+pub struct Block {
+    pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
     pub hash: String,
-    pub nonce: u32,
     pub height: u128,
-    pub timestamp: u128
 }
 
 impl Block {
     pub fn genesis(coinbase: Transaction) -> Self {
-        Block::create_block(String::default(), vec![coinbase], 1)
+        Block::create_block(String::default(), vec![coinbase], 1, INITIAL_DIFFICULTY)
     }
 
-    pub fn create_block(prev_hash: String, transactions: Vec<Transaction>, height: u128) -> Self {
+    pub fn create_block(
+        prev_hash: String,
+        transactions: Vec<Transaction>,
+        height: u128,
+        bits: u8,
+    ) -> Self {
+        let mut new_block = Block::new_template(prev_hash, transactions, height, bits);
+
+        // do the proof of work, nonce耗尽时更新时间戳重试
+        while !new_block.mine(&AtomicBool::new(false)) {
+            new_block.refresh_timestamp();
+        }
+
+        new_block
+    }
 
+    /// 生成尚未完成工作量证明的区块
+    pub fn new_template(
+        prev_hash: String,
+        transactions: Vec<Transaction>,
+        height: u128,
+        bits: u8,
+    ) -> Self {
         let now = SystemTime::now();
         let since_the_epoch = now.duration_since(UNIX_EPOCH).unwrap();
         let timestamp = since_the_epoch.as_millis();
-        // todo: return the solid block, refresh nonce if has traversed it all.
-        let mut new_block = Block {
-            prev_hash,
+        let merkle_root = hex::encode(Block::merkle_root(&transactions));
+
+        Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash,
+                merkle_root,
+                timestamp,
+                bits,
+                nonce: u32::default(),
+            },
             transactions,
             hash: String::default(),
-            nonce: u32::default(),
             height,
-            timestamp
-        };
+        }
+    }
 
-        // do the proof of work
-        let proof_of_work = ProofOfWork::new(&new_block);
-        let cal_result = proof_of_work.run();
+    /// 对区块进行工作量证明, 成功后填入nonce与hash
+    ///
+    /// # Arguments
+    ///
+    /// - `cancel` (`&AtomicBool`) - 置为true时放弃计算
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 是否成功
+    pub fn mine(&mut self, cancel: &AtomicBool) -> bool {
+        let proof_of_work = ProofOfWork::new(&self.header);
+        let cal_result = proof_of_work.run_until(cancel);
 
         // match fail or success
         match cal_result {
             Some((nonce, hash)) => {
-                new_block.nonce = nonce;
-                new_block.hash = hash;
-                return new_block;
-            }
-            None => {
-                let mut err_msg = String::with_capacity(128);
-                err_msg.push_str("Failed to calcute proof of work for block, prev_hash: ");
-                let prev_hash = hex::encode(new_block.prev_hash);
-                err_msg.push_str(&prev_hash);
-                panic!("{}", err_msg);
+                self.header.nonce = nonce;
+                self.hash = hash;
+                true
             }
+            None => false,
+        }
+    }
+
+    /// nonce耗尽后推进时间戳, 让区块头获得新的哈希空间
+    pub fn refresh_timestamp(&mut self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        self.header.timestamp = now.max(self.header.timestamp + 1);
+    }
+
+    /// 区块编码后的字节数
+    pub fn size(&self) -> usize {
+        bincode::encode_to_vec(self, config::standard()).unwrap().len()
+    }
+
+    pub fn header_entry(&self) -> HeaderEntry {
+        HeaderEntry {
+            header: self.header.clone(),
+            hash: self.hash.clone(),
+            height: self.height,
         }
     }
 
     pub fn hash_transactions(&self) -> Vec<u8> {
+        Block::merkle_root(&self.transactions)
+    }
+
+    fn merkle_root(transactions: &[Transaction]) -> Vec<u8> {
         let mut tx_bytes : Vec<Vec<u8>> = vec![];
-        for tx in &self.transactions {
+        for tx in transactions {
             let bytes = bincode::encode_to_vec(tx, config::standard()).unwrap();
             tx_bytes.push(bytes);
         }
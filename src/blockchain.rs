@@ -1,14 +1,166 @@
-use bincode::{Encode, config};
+use bincode::{Decode, Encode, config};
+use ethereum_types::U256;
 use k256::ecdsa::SigningKey;
 use tokio::sync::RwLock;
-use std::{collections::HashMap, fs::{self}, path::PathBuf, sync::Arc};
-use crate::{block::Block, register_exit_callback, transaction::Transaction, tx::TxOutputs};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs::{self},
+    path::PathBuf,
+    sync::{Arc, atomic::AtomicBool},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use crate::{
+    address_index::AddressIndex,
+    block::{Block, BlockHeader, HeaderEntry, MAX_BLOCK_SIZE},
+    emission::EmissionSchedule,
+    proof_of_work::{INITIAL_DIFFICULTY, ProofOfWork, RETARGET_INTERVAL},
+    register_exit_callback,
+    storage::{SledStorage, StagedStorage, Storage, WriteBatch},
+    transaction::{SignError, Transaction},
+    utxo::UTXOSet,
+};
 
 const LATEST_HASH_KEY: &str = "lsh";
+const WORK_PREFIX: &str = "work-";
+// 主链高度 -> 区块hash
+const HEIGHT_PREFIX: &str = "height-";
+// 主链tx_id -> TxLocation, 仅在开启交易索引时维护
+const TX_INDEX_PREFIX: &str = "tx-";
+// 存在时表示交易索引已开启且完整
+const TX_INDEX_KEY: &str = "txindex";
+// 已通过校验但区块体尚未入库的区块头 -> HeaderEntry
+const HEADER_PREFIX: &str = "header-";
+// 区块体尚未入库的区块头 -> 所在分支的累计工作量
+const HEADER_WORK_PREFIX: &str = "hwork-";
+// 最多保存的尚无区块体的区块头数
+const MAX_PENDING_HEADERS: usize = 10_000;
+// 区块头所在分支的工作量最多落后主链顶端多少个同难度区块, 超出时不予保存
+const MAX_HEADER_WORK_LAG: u32 = 500;
+// 清理区块头时删除高度落后主链顶端超过该值的区块头
+const MAX_HEADER_DEPTH: u128 = 500;
+// 区块定位器中逐个列出的最近区块数, 之后步长加倍
+const LOCATOR_DENSE_SPAN: usize = 10;
+// 计算中位时间所用的区块数
+const MEDIAN_TIME_SPAN: usize = 11;
+// 区块时间戳允许超前本地时间的上限, 毫秒
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// 区块被拒绝的原因
+#[derive(Debug)]
+pub enum BlockValidationError {
+    Malformed,
+    HashMismatch,
+    MerkleRootMismatch,
+    TimestampTooOld,
+    TimestampTooNew,
+    InsufficientProofOfWork,
+    NoTransactions,
+    TooLarge(usize),
+    MissingCoinbase,
+    MultipleCoinbase,
+    BadCoinbaseHeight,
+    CoinbaseTooLarge { allowed: u128, actual: u128 },
+    TxIdMismatch(String),
+    DoubleSpend(String),
+    UnexpectedGenesis,
+    UnknownParent,
+    InsufficientChainWork,
+    TooManyPendingHeaders,
+    BadHeight { expected: u128, actual: u128 },
+    BadDifficulty { expected: u8, actual: u8 },
+    MissingInput(String),
+    ImmatureCoinbase(String),
+    OutputsExceedInputs(String),
+    InvalidSignature(String),
+}
+
+impl Display for BlockValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockValidationError::Malformed => f.write_str("malformed block"),
+            BlockValidationError::HashMismatch => {
+                f.write_str("block hash doesn't match its contents")
+            }
+            BlockValidationError::MerkleRootMismatch => {
+                f.write_str("merkle root doesn't match the transactions")
+            }
+            BlockValidationError::TimestampTooOld => {
+                f.write_str("timestamp is earlier than the median time past")
+            }
+            BlockValidationError::TimestampTooNew => {
+                f.write_str("timestamp is too far in the future")
+            }
+            BlockValidationError::InsufficientProofOfWork => {
+                f.write_str("proof of work doesn't meet the target")
+            }
+            BlockValidationError::NoTransactions => f.write_str("block has no transactions"),
+            BlockValidationError::TooLarge(size) => {
+                write!(f, "block is {} bytes, only {} allowed", size, MAX_BLOCK_SIZE)
+            }
+            BlockValidationError::MissingCoinbase => {
+                f.write_str("first transaction is not a coinbase")
+            }
+            BlockValidationError::MultipleCoinbase => f.write_str("more than one coinbase"),
+            BlockValidationError::BadCoinbaseHeight => {
+                f.write_str("coinbase doesn't commit to the block height")
+            }
+            BlockValidationError::CoinbaseTooLarge { allowed, actual } => write!(
+                f,
+                "coinbase pays {}, only {} allowed",
+                actual, allowed
+            ),
+            BlockValidationError::TxIdMismatch(tx_id) => {
+                write!(f, "transaction {} has a wrong id", tx_id)
+            }
+            BlockValidationError::DoubleSpend(tx_id) => {
+                write!(f, "transaction {} double spends an output", tx_id)
+            }
+            BlockValidationError::UnexpectedGenesis => {
+                f.write_str("genesis block on a non-empty chain")
+            }
+            BlockValidationError::UnknownParent => f.write_str("parent block is unknown"),
+            BlockValidationError::InsufficientChainWork => {
+                f.write_str("branch has too little work to overtake the main chain")
+            }
+            BlockValidationError::TooManyPendingHeaders => {
+                f.write_str("too many headers are waiting for their blocks")
+            }
+            BlockValidationError::BadHeight { expected, actual } => {
+                write!(f, "height {} where {} is expected", actual, expected)
+            }
+            BlockValidationError::BadDifficulty { expected, actual } => {
+                write!(f, "difficulty {} where {} is expected", actual, expected)
+            }
+            BlockValidationError::MissingInput(tx_id) => {
+                write!(f, "transaction {} spends a missing or spent output", tx_id)
+            }
+            BlockValidationError::ImmatureCoinbase(tx_id) => {
+                write!(f, "transaction {} spends an immature coinbase", tx_id)
+            }
+            BlockValidationError::OutputsExceedInputs(tx_id) => {
+                write!(f, "transaction {} creates more than it spends", tx_id)
+            }
+            BlockValidationError::InvalidSignature(tx_id) => {
+                write!(f, "transaction {} has an invalid signature", tx_id)
+            }
+        }
+    }
+}
+
+/// 交易在主链上的位置
+#[derive(Debug, Encode, Decode)]
+pub struct TxLocation {
+    pub block_hash: String,
+    // 在区块交易列表中的下标
+    pub position: usize,
+}
 
 pub struct Blockchain {
     pub latest_hash: String,
-    pub database: Arc<RwLock<sled::Db>>,
+    pub database: Arc<RwLock<dyn Storage>>,
+    // 所属网络的挖矿奖励发行计划
+    pub emission: EmissionSchedule,
 }
 
 impl Encode for Blockchain {
@@ -25,6 +177,7 @@ impl std::fmt::Debug for Blockchain {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Blockchain")
             .field("latest_hash", &self.latest_hash)
+            .field("emission", &self.emission)
             .field("database", &"non-debuggable")
             .finish()
     }
@@ -39,39 +192,76 @@ impl Blockchain {
     }
 
     /// 从本地数据库文件初始化区块链
-    pub async fn continue_chain(node_id: u32) -> Self {
+    ///
+    /// # Arguments
+    ///
+    /// - `node_id` (`u32`) - node_id
+    /// - `emission` (`EmissionSchedule`) - 所属网络的发行计划
+    pub async fn continue_chain(node_id: u32, emission: EmissionSchedule) -> Self {
         // if !Blockchain::exists_db(node_id) {
         //     panic!("Blockchain[{}] DB doesn't exist, init one first!", node_id);
         // }
 
         let db_path_str = format!("./blocks_{}", node_id);
         let db_path = PathBuf::from(db_path_str);
+        let storage = SledStorage::open(db_path).expect("Failed to open Sled db!");
+
+        Blockchain::open(storage, emission).await
+    }
 
-        let db_client_mutex = Blockchain::init_db_client(db_path);
-        let db_client = db_client_mutex.read().await;
-        let lsh_value = db_client.get(LATEST_HASH_KEY);
+    /// 从已有的存储初始化区块链
+    pub async fn open(storage: impl Storage + 'static, emission: EmissionSchedule) -> Self {
+        let db_client_mutex = Blockchain::init_db_client(storage);
+        let (lsh_value, utxo_tip) = {
+            let db_client = db_client_mutex.read().await;
+            (
+                db_client.get(LATEST_HASH_KEY.as_bytes()).ok().flatten(),
+                UTXOSet::tip(&*db_client),
+            )
+        };
 
-        if let Some(lsh) = lsh_value.ok().flatten() {
+        let blockchain = if let Some(lsh) = &lsh_value {
             Blockchain {
                 latest_hash: hex::encode(lsh),
                 database: Arc::clone(&db_client_mutex),
+                emission,
             }
         } else {
             Blockchain {
                 latest_hash: String::default(),
                 database: Arc::clone(&db_client_mutex),
+                emission,
             }
             // panic!("Blockchain latest hash doesn't exist, init blockchain first!");
+        };
+
+        // 上次写入被中断, UTXO set与主链顶端不一致, 重放主链修复
+        if lsh_value != utxo_tip {
+            println!("UTXO set doesn't match the chain tip, rebuilding it and the indexes...");
+            blockchain.reindex().await;
+        }
+
+        // 旧版本数据库没有高度索引
+        if let Some(lsh) = &lsh_value {
+            let tip = blockchain.get_block(lsh).await;
+            let indexed = match &tip {
+                Some(tip) => blockchain.get_block_by_height(tip.height).await,
+                None => None,
+            };
+            if indexed.map(|block| block.hash) != tip.map(|block| block.hash) {
+                println!("Height index doesn't match the chain tip, rebuilding it...");
+                blockchain.reindex_heights().await;
+            }
         }
+
+        blockchain
     }
 
     /*
     初始化数据库链接实例
      */
-    fn init_db_client(db_path: PathBuf) -> Arc<RwLock<sled::Db>> {
-        let db = sled::open(db_path).expect("Failed to open Sled db!");
-
-        let db_client_mutex = Arc::new(RwLock::new(db));
+    fn init_db_client(storage: impl Storage + 'static) -> Arc<RwLock<dyn Storage>> {
+        let db_client_mutex: Arc<RwLock<dyn Storage>> = Arc::new(RwLock::new(storage));
         let db_client_callback = Arc::clone(&db_client_mutex);
 
         register_exit_callback(Box::pin(async move {
@@ -79,315 +269,1282 @@ impl Blockchain {
                 .read()
                 .await
                 .flush()
-                .expect("Failed to persist sled");
+                .expect("Failed to persist storage");
         }));
 
         db_client_mutex
     }
 
-    pub async fn init(node_id: u32, to: String) -> Self {
+    /// 按主链重建UTXO set以及所有已开启的索引
+    pub async fn reindex(&self) {
+        UTXOSet::reindex(self).await;
+        self.reindex_heights().await;
+
+        let (tx_index, addr_index) = {
+            let database = self.database.read().await;
+            (
+                Blockchain::tx_index_enabled(&*database),
+                AddressIndex::enabled(&*database),
+            )
+        };
+        if tx_index {
+            self.set_tx_index(true).await;
+        }
+        if addr_index {
+            AddressIndex::set_enabled(self, true).await;
+        }
+    }
+
+    pub async fn init(node_id: u32, to: String, emission: EmissionSchedule) -> Self {
         let db_path_str = format!("./blocks_{}", node_id);
         // 判断本地数据库是否存在
         let db_path = PathBuf::from(db_path_str);
         // if db_path.exists() {
         //     panic!("Blockchain has already existed, just continue it!");
         // }
+        let storage = SledStorage::open(db_path).expect("Failed to open Sled db!");
 
-        let db_client_mutex = Blockchain::init_db_client(db_path);
+        Blockchain::create(storage, to, emission).await
+    }
+
+    /// 在空的存储上创建创世区块, 奖励发给to
+    pub async fn create(
+        storage: impl Storage + 'static,
+        to: String,
+        emission: EmissionSchedule,
+    ) -> Self {
+        let db_client_mutex = Blockchain::init_db_client(storage);
         let db_client = db_client_mutex.write().await;
 
         // init coinbase & genesis block
-        let coinbase_tx = Transaction::coinbase_tx(to);
+        let coinbase_tx = Transaction::coinbase_tx(to, 1, emission.subsidy(1));
         let genesis_block = Block::genesis(coinbase_tx);
         let encoded_block = bincode::encode_to_vec(&genesis_block, config::standard())
             .ok()
             .expect("Failed to init blockchain cause encoding genesis block error");
 
-        // save coinbase & genesis block
-        let block_key = genesis_block.hash.clone();
+        // save coinbase & genesis block, 与UTXO set和主链顶端写入同一个batch
+        let mut batch = WriteBatch::default();
+        batch.put(&genesis_block.hash, encoded_block);
+        Blockchain::save_chain_work(&*db_client, &genesis_block, &mut batch);
+        Blockchain::connect_batch(&*db_client, &genesis_block, &mut batch);
         db_client
-            .insert(&block_key, encoded_block)
+            .apply_batch(batch)
             .expect("Failed to save genesis block");
 
-        let hash_bytes = hex::decode(&block_key).expect(&format!(
-            "Failed to hex::decode block_key {} to bytes",
-            block_key
-        ));
-        db_client
-            .insert(LATEST_HASH_KEY, hash_bytes)
-            .expect("Failed to store latest hash");
-
         return Blockchain {
             latest_hash: genesis_block.hash,
             database: Arc::clone(&db_client_mutex),
+            emission,
         };
     }
 
-    pub async fn add_block(&self, block: Block) {
-        let database = self.database.write().await;
-
-        if database.contains_key(&block.hash).unwrap() {
-            return;
+    /// 添加区块, 并按累计工作量选择主链
+    ///
+    /// 若新区块所在分支的累计工作量超过当前主链, 则回滚到分叉点并切换到新分支,
+    /// 同时更新UTXO set. 未通过校验的区块不会入库
+    ///
+    /// # Arguments
+    ///
+    /// - `block` (`Block`) - 新区块
+    ///
+    /// # Returns
+    ///
+    /// - `Result<(), BlockValidationError>` - 区块被拒绝的原因
+    pub async fn add_block(&self, block: Block) -> Result<(), BlockValidationError> {
+        if self
+            .database
+            .read()
+            .await
+            .contains_key(block.hash.as_bytes())
+            .unwrap()
+        {
+            return Ok(());
         }
 
-        // save new block to DB
-        let encoded_block = bincode::encode_to_vec(&block, config::standard())
-            .expect("Failed to encode new added block");
-        database
-            .insert(&block.hash, encoded_block)
-            .expect("Failed to save new added block");
+        self.validate_block(&block).await?;
+
+        let tip = self
+            .database
+            .read()
+            .await
+            .get(LATEST_HASH_KEY.as_bytes())
+            .ok()
+            .flatten()
+            .map(hex::encode);
+
+        // 直接延长主链的区块在入库前完成交易校验
+        let extends_tip = match &tip {
+            Some(tip) => &block.header.prev_hash == tip,
+            None => true,
+        };
+        if extends_tip {
+            self.validate_transactions(&block).await?;
+        }
 
-        if let Ok(Some(lsh_bytes)) = database.get(LATEST_HASH_KEY) {
-            // load the last block
-            let lsh = hex::encode(lsh_bytes);
+        let (block_work, tip_work) = {
+            let database = self.database.write().await;
 
-            let last_block_bytes = database.get(&lsh).ok().flatten().expect(&format!(
-                "Cannot load the last block from blockchain: {}",
-                &lsh
-            ));
+            // save new block to DB
+            let mut batch = WriteBatch::default();
+            let encoded_block = bincode::encode_to_vec(&block, config::standard())
+                .expect("Failed to encode new added block");
+            batch.put(&block.hash, encoded_block);
+            batch.delete(Blockchain::header_key(&block.hash));
+            batch.delete(Blockchain::header_work_key(&block.hash));
 
-            let (last_block, _): (Block, usize) =
-                bincode::decode_from_slice(&last_block_bytes, config::standard()).unwrap();
+            let block_work = Blockchain::save_chain_work(&*database, &block, &mut batch)
+                .ok_or(BlockValidationError::UnknownParent)?;
+            let tip_work = tip
+                .as_ref()
+                .and_then(|tip| Blockchain::read_chain_work(&*database, tip))
+                .unwrap_or_default();
 
-            // save lastest hash
-            if block.height > last_block.height {
+            // 延长主链时区块、UTXO与主链顶端一并提交
+            if tip.is_none() || (extends_tip && block_work > tip_work) {
+                Blockchain::connect_batch(&*database, &block, &mut batch);
                 database
-                    .insert(
-                        LATEST_HASH_KEY,
-                        hex::decode(&block.hash).expect(&format!(
-                            "Failed to decode hex hash {} to bytes",
-                            &block.hash
-                        )),
-                    )
-                    .expect("Failed to save added block");
-                println!("Added a brand-new higher [lastest hash]!");
+                    .apply_batch(batch)
+                    .expect("Failed to save new added block");
+                println!("Added a brand-new higher [latest hash]!");
+                return Ok(());
             }
-        } else {
+
             database
-                .insert(
-                    LATEST_HASH_KEY,
-                    hex::decode(&block.hash).expect(&format!(
-                        "Failed to decode hex hash {} to bytes",
-                        &block.hash
-                    )),
-                )
-                .expect("Failed to save added block");
-            println!("Added a brand-new higher [latest hash]!");
+                .apply_batch(batch)
+                .expect("Failed to save new added block");
+            (block_work, tip_work)
+        };
+
+        if block_work <= tip_work {
+            println!("Block {} stays on a side branch", &block.hash);
+            return Ok(());
         }
+
+        let tip = tip.expect("Chain tip exists when reorganizing");
+        self.reorganize(&tip, block).await
     }
 
-    /// 根据hash获取目标的Block
-    ///
-    /// # Arguments
-    ///
-    /// - `&self` (`undefined`) - Blockchain
-    /// - `hash` (`&[u8]`) - hash
-    ///
-    /// # Returns
-    ///
-    /// - `Option<Block>` - Block
-    pub async fn get_block(&self, hash: &[u8]) -> Option<Block> {
-        let key = hex::encode(hash);
-        let database = self.database.write().await;
-        let val = database.get(key).ok().flatten();
+    /// 把区块接入主链顶端, UTXO、各项索引与主链顶端写入同一个batch
+    fn connect_batch(database: &dyn Storage, block: &Block, batch: &mut WriteBatch) {
+        let spent = UTXOSet::connect_block(database, block, batch);
+        batch.put(
+            Blockchain::height_key(block.height),
+            hex::decode(&block.hash).unwrap(),
+        );
+        if Blockchain::tx_index_enabled(database) {
+            Blockchain::index_transactions(block, batch);
+        }
+        if AddressIndex::enabled(database) {
+            AddressIndex::connect_block(block, &spent, batch);
+        }
+        Blockchain::set_tip(batch, &block.hash);
+    }
 
-        match val {
-            Some(bytes) => {
-                let (block, _): (Block, usize) =
-                    bincode::decode_from_slice(&bytes, config::standard()).unwrap();
-                return Some(block);
+    /// 从主链顶端回滚区块, UTXO、各项索引与主链顶端写入同一个batch
+    fn disconnect_batch(database: &dyn Storage, block: &Block, batch: &mut WriteBatch) {
+        let restored = UTXOSet::disconnect_block(database, block, batch);
+        batch.delete(Blockchain::height_key(block.height));
+        if Blockchain::tx_index_enabled(database) {
+            for tx in &block.transactions {
+                batch.delete(Blockchain::tx_index_key(&tx.id));
             }
-            None => None,
         }
+        if AddressIndex::enabled(database) {
+            AddressIndex::disconnect_block(block, &restored, batch);
+        }
+        Blockchain::set_tip(batch, &block.header.prev_hash);
     }
 
-    pub async fn get_block_hashes(&self) -> Vec<String> {
-        let mut iter = self.iterator().await;
+    fn tx_index_key(tx_id: &[u8]) -> String {
+        format!("{}{}", TX_INDEX_PREFIX, hex::encode(tx_id))
+    }
 
-        let mut hashes: Vec<String> = vec![];
-        while let Some(block) = iter.next().await {
-            hashes.push(block.hash);
-        }
+    fn tx_index_enabled(database: &dyn Storage) -> bool {
+        database
+            .contains_key(TX_INDEX_KEY.as_bytes())
+            .expect("Failed to read tx index flag")
+    }
 
-        hashes
+    fn index_transactions(block: &Block, batch: &mut WriteBatch) {
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let location = TxLocation {
+                block_hash: block.hash.clone(),
+                position,
+            };
+            let bytes = bincode::encode_to_vec(location, config::standard()).unwrap();
+            batch.put(Blockchain::tx_index_key(&tx.id), bytes);
+        }
     }
 
-    pub async fn mine_block(&self, transactions: Vec<Transaction>) -> Block {
-        // verify all transactions
-        for tx in &transactions {
-            let verify = self.verify_transaction(tx).await;
-            if !verify {
-                let tx_id = hex::encode(&tx.id);
-                panic!("Detected invalid Transaction, id: {}", tx_id);
+    /// 开启或关闭交易索引
+    ///
+    /// 开启时按主链补建全部索引, 关闭时删除已有索引
+    ///
+    /// # Arguments
+    ///
+    /// - `enabled` (`bool`) - 是否开启
+    pub async fn set_tx_index(&self, enabled: bool) {
+        let mut batch = WriteBatch::default();
+        {
+            let database = self.database.read().await;
+            for result in database.scan_prefix(TX_INDEX_PREFIX.as_bytes()) {
+                let (key, _) = result.unwrap();
+                batch.delete(key);
             }
         }
 
-        // get block height
-        let database = self.database.write().await;
-        let last_hash = database.get(LATEST_HASH_KEY).ok().flatten().unwrap();
-        let last_hash_key = hex::encode(last_hash);
+        if enabled {
+            let mut iter = self.iterator().await;
+            while let Some(block) = iter.next().await {
+                Blockchain::index_transactions(&block, &mut batch);
+            }
+            batch.put(TX_INDEX_KEY, vec![]);
+        } else {
+            batch.delete(TX_INDEX_KEY);
+        }
 
-        let last_block_bytes = database.get(last_hash_key).ok().flatten().unwrap();
+        self.database
+            .write()
+            .await
+            .apply_batch(batch)
+            .expect("Failed to update tx index");
+    }
 
-        let (last_block, _): (Block, usize) =
-            bincode::decode_from_slice(&last_block_bytes, config::standard()).unwrap();
+    /// 从旧主链切换到以new_tip为顶端的分支
+    ///
+    /// 回滚与接入都先写入暂存视图, 新分支上的区块逐个校验交易后再接入,
+    /// 全部通过后与主链顶端一起在同一个batch中提交. 若某个区块校验失败,
+    /// 原主链保持不变, 并丢弃该区块
+    ///
+    /// # Arguments
+    ///
+    /// - `old_tip` (`&str`) - 当前主链顶端hash
+    /// - `new_tip` (`Block`) - 新分支顶端区块
+    async fn reorganize(&self, old_tip: &str, new_tip: Block) -> Result<(), BlockValidationError> {
+        let mut old_block = self
+            .get_block(&hex::decode(old_tip).unwrap())
+            .await
+            .expect("Cannot load the current tip block");
+        let mut new_block = new_tip;
 
-        // do mine
-        let new_block =
-            Block::create_block(last_block.hash.clone(), transactions, last_block.height + 1);
+        // 分别回溯两条分支直到分叉点
+        let mut disconnected: Vec<Block> = vec![];
+        let mut connected: Vec<Block> = vec![];
+        while old_block.hash != new_block.hash {
+            let step_old = old_block.height >= new_block.height;
+            let step_new = new_block.height >= old_block.height;
 
-        // save new block & update lsh
-        let new_block_bytes = bincode::encode_to_vec(&new_block, config::standard()).unwrap();
-        database.insert(&new_block.hash, new_block_bytes).unwrap();
-        let hash_bytes = hex::decode(&new_block.hash).unwrap();
-        database.insert(LATEST_HASH_KEY, hash_bytes).unwrap();
+            if step_old {
+                let parent = self
+                    .get_parent(&old_block)
+                    .await
+                    .ok_or(BlockValidationError::UnknownParent)?;
+                disconnected.push(std::mem::replace(&mut old_block, parent));
+            }
+            if step_new {
+                let parent = self
+                    .get_parent(&new_block)
+                    .await
+                    .ok_or(BlockValidationError::UnknownParent)?;
+                connected.push(std::mem::replace(&mut new_block, parent));
+            }
+        }
 
-        new_block
-    }
+        println!(
+            "Reorganize at fork {}: disconnect {} block(s), connect {} block(s)",
+            &old_block.hash,
+            disconnected.len(),
+            connected.len()
+        );
 
-    pub async fn get_height(&self) -> u128 {
-        let database = self.database.read().await;
-        if let Some(lsh) = database.get(LATEST_HASH_KEY).ok().flatten() {
-            let lastest_hash = hex::encode(lsh);
-            if let Some(block_bytes) = database.get(lastest_hash).ok().flatten() {
-                let (block, _): (Block, usize) =
-                    bincode::decode_from_slice(&block_bytes, config::standard()).unwrap();
-                return block.height;
+        let database = self.database.write().await;
+        // 每一步都在暂存视图中同步移动主链顶端, 保证按主链查找Tx时与UTXO一致
+        let staged = StagedStorage::new(&*database);
+        for block in &disconnected {
+            let mut batch = WriteBatch::default();
+            Blockchain::disconnect_batch(&staged, block, &mut batch);
+            staged.apply_batch(batch).expect("Failed to stage disconnected block");
+        }
+
+        for (i, block) in connected.iter().enumerate().rev() {
+            if let Err(err) = self.check_transactions(&staged, block) {
+                println!("Abort reorganization, block {} is invalid: {}", &block.hash, err);
+                drop(staged);
+                // 无效区块及其之上直到new_tip的区块都不可能再接入主链
+                let invalid = connected[..=i].iter().map(|block| block.hash.as_str());
+                database
+                    .apply_batch(Blockchain::forget_batch(invalid))
+                    .expect("Failed to remove blocks");
+                return Err(err);
             }
+
+            let mut batch = WriteBatch::default();
+            Blockchain::connect_batch(&staged, block, &mut batch);
+            staged.apply_batch(batch).expect("Failed to stage connected block");
         }
 
-        return 0;
+        database
+            .apply_batch(staged.into_batch())
+            .expect("Failed to reorganize");
+
+        Ok(())
     }
 
-    pub async fn iterator(&self) -> Iterator {
-        let database = self.database.read().await;
-        if let Some(lsh) = database.get(LATEST_HASH_KEY).ok().flatten() {
-            return Iterator {
-                database: Arc::clone(&self.database),
-                current_hash: hex::encode(lsh),
-            };
-        }
-        Iterator {
-            database: Arc::clone(&self.database),
-            current_hash: String::default(),
+    /// 删除无效区块及其索引
+    fn forget_batch<'a>(hashes: impl IntoIterator<Item = &'a str>) -> WriteBatch {
+        let mut batch = WriteBatch::default();
+        for hash in hashes {
+            batch.delete(hash);
+            batch.delete(format!("{}{}", WORK_PREFIX, hash));
+            batch.delete(Blockchain::header_key(hash));
+            batch.delete(Blockchain::header_work_key(hash));
         }
+        batch
     }
 
-    /*
-     * 寻找某地址所有未支付Output
-     */
-    pub async fn find_utxos(&self) -> HashMap<String, TxOutputs> {
-        let mut iter = self.iterator().await;
-        let mut utxos = HashMap::<String, TxOutputs>::default();
-        let mut spent_outputs = HashMap::<String, Vec<usize>>::default();
+    /// 校验区块本身及其与父区块的关系, 不依赖UTXO
+    ///
+    /// # Arguments
+    ///
+    /// - `block` (`&Block`) - 待校验区块
+    ///
+    /// # Returns
+    ///
+    /// - `Result<(), BlockValidationError>` - 区块被拒绝的原因
+    pub async fn validate_block(&self, block: &Block) -> Result<(), BlockValidationError> {
+        Blockchain::check_block(block)?;
+        self.validate_header_context(&block.header, block.height)
+            .await
+    }
 
-        while let Some(block) = iter.next().await {
-            for tx in block.transactions {
-                let tx_id = hex::encode(&tx.id);
+    /// 不依赖链上数据的区块校验, 父区块未知的区块也能完成
+    ///
+    /// # Arguments
+    ///
+    /// - `block` (`&Block`) - 待校验区块
+    ///
+    /// # Returns
+    ///
+    /// - `Result<(), BlockValidationError>` - 区块被拒绝的原因
+    pub fn check_block(block: &Block) -> Result<(), BlockValidationError> {
+        Blockchain::check_header(&block.header, &block.hash)?;
 
-                // process tx inputs
-                if !tx.is_coinbase() {
-                    for input in tx.inputs {
-                        spent_outputs
-                            .entry(tx_id.clone())
-                            .or_insert_with(Vec::new)
-                            .push(input.out_idx);
-                    }
-                }
+        let size = block.size();
+        if size > MAX_BLOCK_SIZE {
+            return Err(BlockValidationError::TooLarge(size));
+        }
+        let Some(coinbase) = block.transactions.first() else {
+            return Err(BlockValidationError::NoTransactions);
+        };
+        if hex::encode(block.hash_transactions()) != block.header.merkle_root {
+            return Err(BlockValidationError::MerkleRootMismatch);
+        }
+        if !coinbase.is_coinbase() {
+            return Err(BlockValidationError::MissingCoinbase);
+        }
+        if coinbase.coinbase_height() != Some(block.height) {
+            return Err(BlockValidationError::BadCoinbaseHeight);
+        }
+        if block.transactions[1..].iter().any(|tx| tx.is_coinbase()) {
+            return Err(BlockValidationError::MultipleCoinbase);
+        }
 
-                for (idx, output) in tx.outputs.into_iter().enumerate() {
-                    if spent_outputs.contains_key(&tx_id) {
-                        if spent_outputs.get(&tx_id).unwrap().contains(&idx) {
-                            continue;
-                        }
-                    }
-
-                    utxos
-                        .entry(tx_id.clone())
-                        .or_insert_with(TxOutputs::default)
-                        .push(output);
+        let mut spent = HashSet::new();
+        for tx in &block.transactions {
+            if tx.id != tx.hash() {
+                return Err(BlockValidationError::TxIdMismatch(hex::encode(&tx.id)));
+            }
+            if tx.is_coinbase() {
+                continue;
+            }
+            for input in &tx.inputs {
+                if !spent.insert((input.tx_id.clone(), input.out_idx)) {
+                    return Err(BlockValidationError::DoubleSpend(hex::encode(&tx.id)));
                 }
             }
         }
 
-        utxos
+        Ok(())
     }
 
-    /// 寻找目标Transaction
+    /// 校验区块头: 工作量证明、时间戳以及与父区块的衔接
     ///
     /// # Arguments
     ///
-    /// - `&mut self` (`undefined`)
-    /// - `tx_id` (`&Vec<u8>`) - tx_id
+    /// - `header` (`&BlockHeader`) - 待校验区块头
+    /// - `hash` (`&str`) - 区块声明的hash
+    /// - `height` (`u128`) - 区块声明的高度
     ///
     /// # Returns
     ///
-    /// - `Transaction` - 事务.
-    pub async fn find_transaction(&self, tx_id: &[u8]) -> Transaction {
-        let mut iter = self.iterator().await;
-        while let Some(block) = iter.next().await {
-            for tx in block.transactions {
-                if &tx.id == tx_id {
-                    return tx;
-                }
+    /// - `Result<(), BlockValidationError>` - 区块被拒绝的原因
+    pub async fn validate_header(
+        &self,
+        header: &BlockHeader,
+        hash: &str,
+        height: u128,
+    ) -> Result<(), BlockValidationError> {
+        Blockchain::check_header(header, hash)?;
+        self.validate_header_context(header, height).await
+    }
+
+    /// 校验区块头与父区块的衔接: 高度、难度以及时间戳不早于中位时间
+    async fn validate_header_context(
+        &self,
+        header: &BlockHeader,
+        height: u128,
+    ) -> Result<(), BlockValidationError> {
+        if header.prev_hash.is_empty() {
+            if self.database.read().await.contains_key(LATEST_HASH_KEY.as_bytes()).unwrap() {
+                return Err(BlockValidationError::UnexpectedGenesis);
+            }
+            if height != 1 {
+                return Err(BlockValidationError::BadHeight {
+                    expected: 1,
+                    actual: height,
+                });
             }
+            if header.bits != INITIAL_DIFFICULTY {
+                return Err(BlockValidationError::BadDifficulty {
+                    expected: INITIAL_DIFFICULTY,
+                    actual: header.bits,
+                });
+            }
+            return Ok(());
+        }
+
+        // 父区块可能只有区块头, 区块体尚未下载
+        let Some(parent) = self.get_header(&header.prev_hash).await else {
+            return Err(BlockValidationError::UnknownParent);
+        };
+        if height != parent.height + 1 {
+            return Err(BlockValidationError::BadHeight {
+                expected: parent.height + 1,
+                actual: height,
+            });
+        }
+        let difficulty = self.next_difficulty(&parent).await;
+        if header.bits != difficulty {
+            return Err(BlockValidationError::BadDifficulty {
+                expected: difficulty,
+                actual: header.bits,
+            });
+        }
+        if header.timestamp < self.median_time_past(&parent).await {
+            return Err(BlockValidationError::TimestampTooOld);
         }
 
-        panic!("Transaction: {} doesn't exist", hex::encode(tx_id));
+        Ok(())
     }
 
-    /// 给Tx签名
+    /// 不依赖链上数据的区块头校验: 格式、hash以及是否满足区块头声明的难度
     ///
     /// # Arguments
     ///
-    /// - `tx` (`&Transaction`) - 待签名的Tx
-    /// - `priv_key` (`&SigningKey`) - 签名的私钥
+    /// - `header` (`&BlockHeader`) - 待校验区块头
+    /// - `hash` (`&str`) - 区块声明的hash
+    ///
     /// # Returns
     ///
-    pub async fn sign_transaction(&self, tx_to_sign: &mut Transaction, priv_key: &mut SigningKey) {
-        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
-        for input in &tx_to_sign.inputs {
-            let tx = self.find_transaction(&input.tx_id).await;
-            prev_txs.insert(hex::encode(&input.tx_id), tx);
+    /// - `Result<(), BlockValidationError>` - 区块头被拒绝的原因
+    pub fn check_header(header: &BlockHeader, hash: &str) -> Result<(), BlockValidationError> {
+        if hex::decode(&header.prev_hash).is_err() || hex::decode(&header.merkle_root).is_err() {
+            return Err(BlockValidationError::Malformed);
+        }
+
+        let pow = ProofOfWork::new(header);
+        if pow.hash() != hash {
+            return Err(BlockValidationError::HashMismatch);
+        }
+        if !pow.validate(header.bits) {
+            return Err(BlockValidationError::InsufficientProofOfWork);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        if header.timestamp > now + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockValidationError::TimestampTooNew);
         }
 
-        tx_to_sign.sign(priv_key, prev_txs);
+        Ok(())
     }
 
-    /// 校验某个Tx
+    /// 以entry为终点的最近MEDIAN_TIME_SPAN个区块时间戳的中位数
+    async fn median_time_past(&self, entry: &HeaderEntry) -> u128 {
+        let mut timestamps = vec![entry.header.timestamp];
+        let mut current_hash = entry.header.prev_hash.clone();
+        while timestamps.len() < MEDIAN_TIME_SPAN && !current_hash.is_empty() {
+            let Some(ancestor) = self.get_header(&current_hash).await else {
+                break;
+            };
+            timestamps.push(ancestor.header.timestamp);
+            current_hash = ancestor.header.prev_hash;
+        }
+
+        timestamps.sort();
+        timestamps[timestamps.len() / 2]
+    }
+
+    /// 基于UTXO校验区块内的交易, 要求UTXO set正好对应block的父区块
+    async fn validate_transactions(&self, block: &Block) -> Result<(), BlockValidationError> {
+        let database = self.database.read().await;
+        self.check_transactions(&*database, block)
+    }
+
+    /// 基于database中的UTXO校验区块内的交易, database的主链顶端需正好是block的父区块
     ///
-    /// # Returns
+    /// # Arguments
     ///
-    /// - `bool` - 是否通过校验
+    /// - `database` (`&dyn Storage`) - 数据库或暂存视图
+    /// - `block` (`&Block`) - 待校验区块
     ///
-    pub async fn verify_transaction(&self, tx_to_verify: &Transaction) -> bool {
-        let mut prev_txs: HashMap<String, Transaction> = HashMap::default();
+    /// # Returns
+    ///
+    /// - `Result<(), BlockValidationError>` - 区块被拒绝的原因
+    fn check_transactions(
+        &self,
+        database: &dyn Storage,
+        block: &Block,
+    ) -> Result<(), BlockValidationError> {
+        let mut fees = 0u128;
+        for tx in block.transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            fees += Blockchain::check_transaction(database, tx, block.height)?;
+        }
 
-        for input in &tx_to_verify.inputs {
-            let tx = self.find_transaction(&input.tx_id).await;
-            prev_txs.insert(hex::encode(&input.tx_id), tx);
+        // coinbase最多领取挖矿奖励与手续费之和
+        let allowed = self.emission.subsidy(block.height) + fees;
+        let coinbase = &block.transactions[0];
+        let coinbase_amount = coinbase
+            .outputs
+            .iter()
+            .fold(0u128, |sum, output| sum.saturating_add(output.amount));
+        if coinbase_amount > allowed {
+            return Err(BlockValidationError::CoinbaseTooLarge {
+                allowed,
+                actual: coinbase_amount,
+            });
         }
 
-        tx_to_verify.verity(prev_txs)
+        Ok(())
     }
-}
 
-pub struct Iterator {
-    pub database: Arc<RwLock<sled::Db>>,
-    pub current_hash: String,
-}
+    /// 基于主链的UTXO校验单个非coinbase交易: 输入未花费、金额守恒以及签名
+    ///
+    /// # Arguments
+    ///
+    /// - `tx` (`&Transaction`) - 待校验交易
+    /// - `height` (`u128`) - 交易所在(或将要打包进)的区块高度
+    ///
+    /// # Returns
+    ///
+    /// - `Result<u128, BlockValidationError>` - 交易的手续费, 或被拒绝的原因
+    pub async fn validate_transaction(
+        &self,
+        tx: &Transaction,
+        height: u128,
+    ) -> Result<u128, BlockValidationError> {
+        let database = self.database.read().await;
+        Blockchain::check_transaction(&*database, tx, height)
+    }
 
-impl Iterator {
-    pub async fn next(&mut self) -> Option<Block> {
-        if &self.current_hash == "" {
-            return None;
+    fn check_transaction(
+        database: &dyn Storage,
+        tx: &Transaction,
+        height: u128,
+    ) -> Result<u128, BlockValidationError> {
+        let tx_id = hex::encode(&tx.id);
+
+        let mut input_amount = 0u128;
+        for input in &tx.inputs {
+            let Some(utxo) = UTXOSet::read_output(database, &input.tx_id, input.out_idx) else {
+                return Err(BlockValidationError::MissingInput(tx_id));
+            };
+            if !utxo.is_mature(height) {
+                return Err(BlockValidationError::ImmatureCoinbase(tx_id));
+            }
+            input_amount += utxo.output.amount;
         }
-        let database = self.database.write().await;
 
-        let encoded_data = database
-            .get(&self.current_hash)
-            .ok()
-            .flatten()
+        // 溢出同样视为超额
+        let output_amount = tx
+            .outputs
+            .iter()
+            .try_fold(0u128, |sum, output| sum.checked_add(output.amount));
+        let Some(output_amount) = output_amount.filter(|amount| *amount <= input_amount) else {
+            return Err(BlockValidationError::OutputsExceedInputs(tx_id));
+        };
+
+        if !Blockchain::check_signatures(database, tx) {
+            return Err(BlockValidationError::InvalidSignature(tx_id));
+        }
+
+        Ok(input_amount - output_amount)
+    }
+
+    /// 获取父区块, 父区块未存储时为None
+    async fn get_parent(&self, block: &Block) -> Option<Block> {
+        let prev_hash = hex::decode(&block.header.prev_hash).ok()?;
+        self.get_block(&prev_hash).await
+    }
+
+    fn set_tip(batch: &mut WriteBatch, hash: &str) {
+        batch.put(
+            LATEST_HASH_KEY,
+            hex::decode(hash)
+                .unwrap_or_else(|_| panic!("Failed to decode hex hash {} to bytes", hash)),
+        );
+    }
+
+    /// 记录区块的累计工作量
+    ///
+    /// # Returns
+    ///
+    /// - `Option<U256>` - 累计工作量, 父区块未知时为None
+    fn save_chain_work(
+        database: &dyn Storage,
+        block: &Block,
+        batch: &mut WriteBatch,
+    ) -> Option<U256> {
+        let parent_work = if block.header.prev_hash.is_empty() {
+            U256::zero()
+        } else {
+            Blockchain::read_chain_work(database, &block.header.prev_hash)?
+        };
+
+        let chain_work = parent_work.saturating_add(ProofOfWork::work(block.header.bits));
+        batch.put(
+            format!("{}{}", WORK_PREFIX, &block.hash),
+            chain_work.to_big_endian().to_vec(),
+        );
+
+        Some(chain_work)
+    }
+
+    fn read_chain_work(database: &dyn Storage, hash: &str) -> Option<U256> {
+        let key = format!("{}{}", WORK_PREFIX, hash);
+        database
+            .get(key.as_bytes())
+            .ok()
+            .flatten()
+            .map(|bytes| U256::from_big_endian(&bytes))
+    }
+
+    /// 获取区块的累计工作量
+    pub async fn get_chain_work(&self, hash: &str) -> Option<U256> {
+        let database = self.database.read().await;
+        Blockchain::read_chain_work(&*database, hash)
+    }
+
+    /// 根据hash获取目标的Block
+    ///
+    /// # Arguments
+    ///
+    /// - `&self` (`undefined`) - Blockchain
+    /// - `hash` (`&[u8]`) - hash
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Block>` - Block
+    pub async fn get_block(&self, hash: &[u8]) -> Option<Block> {
+        let database = self.database.read().await;
+        Blockchain::read_block(&*database, &hex::encode(hash))
+    }
+
+    fn read_block(database: &dyn Storage, hash: &str) -> Option<Block> {
+        let bytes = database.get(hash.as_bytes()).ok().flatten()?;
+        let (block, _): (Block, usize) =
+            bincode::decode_from_slice(&bytes, config::standard()).unwrap();
+        Some(block)
+    }
+
+    fn height_key(height: u128) -> String {
+        format!("{}{}", HEIGHT_PREFIX, height)
+    }
+
+    /// 根据高度获取主链上的Block
+    ///
+    /// # Arguments
+    ///
+    /// - `height` (`u128`) - 区块高度, 创世区块为1
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Block>` - 超出主链高度时为None
+    pub async fn get_block_by_height(&self, height: u128) -> Option<Block> {
+        let key = Blockchain::height_key(height);
+        let hash = self.database.read().await.get(key.as_bytes()).ok().flatten()?;
+        self.get_block(&hash).await
+    }
+
+    fn header_key(hash: &str) -> String {
+        format!("{}{}", HEADER_PREFIX, hash)
+    }
+
+    /// 根据hash获取区块头, 区块体未入库时读取已校验的区块头
+    ///
+    /// # Arguments
+    ///
+    /// - `hash` (`&str`) - 区块hash
+    ///
+    /// # Returns
+    ///
+    /// - `Option<HeaderEntry>` - 区块头及其高度
+    pub async fn get_header(&self, hash: &str) -> Option<HeaderEntry> {
+        let hash_bytes = hex::decode(hash).ok()?;
+        if let Some(block) = self.get_block(&hash_bytes).await {
+            return Some(block.header_entry());
+        }
+
+        let key = Blockchain::header_key(hash);
+        let val = self.database.read().await.get(key.as_bytes()).ok().flatten()?;
+        let (entry, _): (HeaderEntry, usize) =
+            bincode::decode_from_slice(&val, config::standard()).unwrap();
+        Some(entry)
+    }
+
+    /// 区块体是否已入库
+    pub async fn has_block(&self, hash: &str) -> bool {
+        self.database
+            .read()
+            .await
+            .contains_key(hash.as_bytes())
+            .unwrap()
+    }
+
+    fn header_work_key(hash: &str) -> String {
+        format!("{}{}", HEADER_WORK_PREFIX, hash)
+    }
+
+    /// 区块或尚无区块体的区块头所在分支的累计工作量
+    fn read_branch_work(database: &dyn Storage, hash: &str) -> Option<U256> {
+        if let Some(work) = Blockchain::read_chain_work(database, hash) {
+            return Some(work);
+        }
+        database
+            .get(Blockchain::header_work_key(hash).as_bytes())
+            .ok()
+            .flatten()
+            .map(|bytes| U256::from_big_endian(&bytes))
+    }
+
+    /// 依次校验并保存从其他节点收到的区块头, 遇到第一个被拒绝的区块头即停止
+    ///
+    /// 只保存所在分支有望超过主链的区块头: 累计工作量落后主链顶端不超过
+    /// MAX_HEADER_WORK_LAG个同难度区块, 且尚无区块体的区块头不超过MAX_PENDING_HEADERS个,
+    /// 达到上限时先清理无望超过主链的区块头
+    ///
+    /// # Arguments
+    ///
+    /// - `headers` (`Vec<BlockHeader>`) - 区块头, 每个区块头的父区块头必须已知
+    ///
+    /// # Returns
+    ///
+    /// - `(Vec<HeaderEntry>, Option<BlockValidationError>)` - 已接受的区块头及其高度,
+    ///   以及停止的原因
+    pub async fn accept_headers(
+        &self,
+        headers: Vec<BlockHeader>,
+    ) -> (Vec<HeaderEntry>, Option<BlockValidationError>) {
+        let mut pending = self
+            .database
+            .read()
+            .await
+            .scan_prefix(HEADER_PREFIX.as_bytes())
+            .count();
+        if pending >= MAX_PENDING_HEADERS {
+            pending = self.prune_headers().await;
+        }
+
+        let mut accepted = vec![];
+        for header in headers {
+            match self.accept_header(header, &mut pending).await {
+                Ok(entry) => accepted.push(entry),
+                Err(err) => return (accepted, Some(err)),
+            }
+        }
+
+        (accepted, None)
+    }
+
+    /// 删除所在分支的工作量不超过主链顶端, 或高度落后主链顶端超过MAX_HEADER_DEPTH的区块头
+    ///
+    /// # Returns
+    ///
+    /// - `usize` - 剩余的尚无区块体的区块头数
+    async fn prune_headers(&self) -> usize {
+        let height = self.get_height().await;
+        let database = self.database.write().await;
+        let tip_work = database
+            .get(LATEST_HASH_KEY.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|tip| Blockchain::read_chain_work(&*database, &hex::encode(tip)))
+            .unwrap_or_default();
+
+        let mut batch = WriteBatch::default();
+        let mut remaining = 0;
+        for result in database.scan_prefix(HEADER_PREFIX.as_bytes()) {
+            let (_, val) = result.unwrap();
+            let (entry, _): (HeaderEntry, usize) =
+                bincode::decode_from_slice(&val, config::standard()).unwrap();
+            let work = Blockchain::read_branch_work(&*database, &entry.hash).unwrap_or_default();
+            if work <= tip_work || entry.height + MAX_HEADER_DEPTH < height {
+                batch.delete(Blockchain::header_key(&entry.hash));
+                batch.delete(Blockchain::header_work_key(&entry.hash));
+            } else {
+                remaining += 1;
+            }
+        }
+        database
+            .apply_batch(batch)
+            .expect("Failed to prune block headers");
+        remaining
+    }
+
+    /// 删除不再下载区块体的区块头, 区块体已入库的区块不受影响
+    ///
+    /// # Arguments
+    ///
+    /// - `hashes` (`&[String]`) - 区块hash
+    pub async fn forget_headers(&self, hashes: &[String]) {
+        if hashes.is_empty() {
+            return;
+        }
+        let mut batch = WriteBatch::default();
+        for hash in hashes {
+            batch.delete(Blockchain::header_key(hash));
+            batch.delete(Blockchain::header_work_key(hash));
+        }
+        self.database
+            .write()
+            .await
+            .apply_batch(batch)
+            .expect("Failed to remove block headers");
+    }
+
+    /// 校验并保存单个区块头
+    ///
+    /// # Arguments
+    ///
+    /// - `header` (`BlockHeader`) - 区块头
+    /// - `pending` (`&mut usize`) - 当前尚无区块体的区块头数, 保存后加一
+    ///
+    /// # Returns
+    ///
+    /// - `Result<HeaderEntry, BlockValidationError>` - 区块头及其高度, 或被拒绝的原因
+    async fn accept_header(
+        &self,
+        header: BlockHeader,
+        pending: &mut usize,
+    ) -> Result<HeaderEntry, BlockValidationError> {
+        let hash = ProofOfWork::new(&header).hash();
+        if let Some(entry) = self.get_header(&hash).await {
+            return Ok(entry);
+        }
+
+        let Some(parent) = self.get_header(&header.prev_hash).await else {
+            return Err(BlockValidationError::UnknownParent);
+        };
+        let height = parent.height + 1;
+        self.validate_header(&header, &hash, height).await?;
+
+        let database = self.database.write().await;
+        // 旧版本保存的区块头没有累计工作量, 视为无法超过主链
+        let work = Blockchain::read_branch_work(&*database, &parent.hash)
+            .unwrap_or_default()
+            .saturating_add(ProofOfWork::work(header.bits));
+        let tip_work = database
+            .get(LATEST_HASH_KEY.as_bytes())
+            .ok()
+            .flatten()
+            .and_then(|tip| Blockchain::read_chain_work(&*database, &hex::encode(tip)))
+            .unwrap_or_default();
+        let lag = ProofOfWork::work(header.bits).saturating_mul(U256::from(MAX_HEADER_WORK_LAG));
+        if work.saturating_add(lag) <= tip_work {
+            return Err(BlockValidationError::InsufficientChainWork);
+        }
+        if *pending >= MAX_PENDING_HEADERS {
+            return Err(BlockValidationError::TooManyPendingHeaders);
+        }
+
+        let entry = HeaderEntry {
+            header,
+            hash,
+            height,
+        };
+        let mut batch = WriteBatch::default();
+        let bytes = bincode::encode_to_vec(&entry, config::standard()).unwrap();
+        batch.put(Blockchain::header_key(&entry.hash), bytes);
+        batch.put(
+            Blockchain::header_work_key(&entry.hash),
+            work.to_big_endian().to_vec(),
+        );
+        database
+            .apply_batch(batch)
+            .expect("Failed to save block header");
+        *pending += 1;
+
+        Ok(entry)
+    }
+
+    /// 生成主链的区块定位器: 靠近顶端的区块逐个列出, 之后步长加倍, 最后一项总是创世区块
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<String>` - 从顶端到创世区块的区块hash, 链为空时为空
+    pub async fn block_locator(&self) -> Vec<String> {
+        let mut locator = vec![];
+        let mut height = self.get_height().await;
+        let mut step = 1;
+
+        while height > 0 {
+            if let Some(block) = self.get_block_by_height(height).await {
+                locator.push(block.hash);
+            }
+            if height == 1 {
+                break;
+            }
+            if locator.len() >= LOCATOR_DENSE_SPAN {
+                step *= 2;
+            }
+            height = height.saturating_sub(step).max(1);
+        }
+
+        locator
+    }
+
+    /// 根据对方的区块定位器找到分叉点, 返回其后的主链区块头
+    ///
+    /// # Arguments
+    ///
+    /// - `locator` (`&[String]`) - 对方的区块定位器
+    /// - `stop_hash` (`&str`) - 到该区块为止, 为空时只受limit限制
+    /// - `limit` (`usize`) - 最多返回的区块头数量
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<BlockHeader>` - 按高度从低到高的区块头
+    pub async fn headers_after(
+        &self,
+        locator: &[String],
+        stop_hash: &str,
+        limit: usize,
+    ) -> Vec<BlockHeader> {
+        // 定位器中第一个位于本地主链上的区块即为分叉点, 都不在时从创世区块开始
+        let mut fork_height = 0;
+        for hash in locator {
+            let Some(entry) = self.get_header(hash).await else {
+                continue;
+            };
+            let on_main_chain = self
+                .get_block_by_height(entry.height)
+                .await
+                .is_some_and(|block| &block.hash == hash);
+            if on_main_chain {
+                fork_height = entry.height;
+                break;
+            }
+        }
+
+        let mut headers = vec![];
+        let mut height = fork_height + 1;
+        while headers.len() < limit {
+            let Some(block) = self.get_block_by_height(height).await else {
+                break;
+            };
+            headers.push(block.header);
+            if block.hash == stop_hash {
+                break;
+            }
+            height += 1;
+        }
+
+        headers
+    }
+
+    /// 清空并按主链重建高度索引
+    async fn reindex_heights(&self) {
+        let mut batch = WriteBatch::default();
+        {
+            let database = self.database.read().await;
+            for result in database.scan_prefix(HEIGHT_PREFIX.as_bytes()) {
+                let (key, _) = result.unwrap();
+                batch.delete(key);
+            }
+        }
+
+        let mut iter = self.iterator().await;
+        while let Some(block) = iter.next().await {
+            batch.put(
+                Blockchain::height_key(block.height),
+                hex::decode(&block.hash).unwrap(),
+            );
+        }
+
+        self.database
+            .write()
+            .await
+            .apply_batch(batch)
+            .expect("Failed to rebuild height index");
+    }
+
+    pub async fn mine_block(&self, transactions: Vec<Transaction>) -> Block {
+        // verify all transactions
+        for tx in transactions.iter().filter(|tx| !tx.is_coinbase()) {
+            let verify = self.verify_transaction(tx).await;
+            if !verify {
+                let tx_id = hex::encode(&tx.id);
+                panic!("Detected invalid Transaction, id: {}", tx_id);
+            }
+        }
+
+        // do mine
+        let mut new_block = self
+            .block_template(transactions)
+            .await
+            .expect("Blockchain is empty or the block is too large!");
+        // nonce耗尽时更新时间戳重试
+        while !new_block.mine(&AtomicBool::new(false)) {
+            new_block.refresh_timestamp();
+        }
+
+        // save new block, update UTXO set & lsh atomically
+        let database = self.database.write().await;
+        let mut batch = WriteBatch::default();
+        let new_block_bytes = bincode::encode_to_vec(&new_block, config::standard()).unwrap();
+        batch.put(&new_block.hash, new_block_bytes);
+        Blockchain::save_chain_work(&*database, &new_block, &mut batch);
+        Blockchain::connect_batch(&*database, &new_block, &mut batch);
+        database
+            .apply_batch(batch)
+            .expect("Failed to save mined block");
+
+        new_block
+    }
+
+    /// 基于当前主链顶端生成待挖的区块
+    ///
+    /// # Arguments
+    ///
+    /// - `transactions` (`Vec<Transaction>`) - 区块包含的交易, coinbase在首位
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Block>` - 未完成工作量证明的区块, 链为空或区块超过MAX_BLOCK_SIZE时为None
+    pub async fn block_template(&self, transactions: Vec<Transaction>) -> Option<Block> {
+        let last_hash = self.database.read().await.get(LATEST_HASH_KEY.as_bytes()).ok().flatten()?;
+        let last_block = self.get_block(&last_hash).await?;
+
+        let difficulty = self.next_difficulty(&last_block.header_entry()).await;
+        let template = Block::new_template(
+            last_block.hash.clone(),
+            transactions,
+            last_block.height + 1,
+            difficulty,
+        );
+        // 超过上限的区块挖出后也会被拒绝
+        let size = template.size();
+        if size > MAX_BLOCK_SIZE {
+            println!("Block template is {} bytes, larger than {}", size, MAX_BLOCK_SIZE);
+            return None;
+        }
+        Some(template)
+    }
+
+    /// 计算紧跟在prev之后的区块应具备的难度
+    ///
+    /// 每RETARGET_INTERVAL个区块根据前一个窗口的出块耗时调整一次, 其余区块沿用父区块难度
+    ///
+    /// # Arguments
+    ///
+    /// - `prev` (`&HeaderEntry`) - 父区块的区块头
+    ///
+    /// # Returns
+    ///
+    /// - `u8` - 新区块的难度
+    pub async fn next_difficulty(&self, prev: &HeaderEntry) -> u8 {
+        if !prev.height.is_multiple_of(RETARGET_INTERVAL) {
+            return prev.header.bits;
+        }
+
+        // 回溯到窗口内的第一个区块
+        let mut first_timestamp = prev.header.timestamp;
+        let mut current_hash = prev.header.prev_hash.clone();
+        for _ in 1..RETARGET_INTERVAL {
+            match self.get_header(&current_hash).await {
+                Some(ancestor) => {
+                    first_timestamp = ancestor.header.timestamp;
+                    current_hash = ancestor.header.prev_hash;
+                }
+                None => break,
+            }
+        }
+
+        let actual_timespan = prev.header.timestamp.saturating_sub(first_timestamp);
+        ProofOfWork::retarget(prev.header.bits, actual_timespan)
+    }
+
+    /// 链规则要求block具备的难度
+    ///
+    /// # Returns
+    ///
+    /// - `Result<u8, BlockValidationError>` - 难度, 父区块未知时无法推导
+    pub async fn required_difficulty(&self, block: &Block) -> Result<u8, BlockValidationError> {
+        if block.header.prev_hash.is_empty() {
+            return Ok(INITIAL_DIFFICULTY);
+        }
+
+        let prev = self
+            .get_header(&block.header.prev_hash)
+            .await
+            .ok_or(BlockValidationError::UnknownParent)?;
+        Ok(self.next_difficulty(&prev).await)
+    }
+
+    pub async fn get_height(&self) -> u128 {
+        let database = self.database.read().await;
+        if let Some(lsh) = database.get(LATEST_HASH_KEY.as_bytes()).ok().flatten() {
+            let lastest_hash = hex::encode(lsh);
+            if let Some(block_bytes) = database.get(lastest_hash.as_bytes()).ok().flatten() {
+                let (block, _): (Block, usize) =
+                    bincode::decode_from_slice(&block_bytes, config::standard()).unwrap();
+                return block.height;
+            }
+        }
+
+        return 0;
+    }
+
+    pub async fn iterator(&self) -> Iterator {
+        let database = self.database.read().await;
+        if let Some(lsh) = database.get(LATEST_HASH_KEY.as_bytes()).ok().flatten() {
+            return Iterator {
+                database: Arc::clone(&self.database),
+                current_hash: hex::encode(lsh),
+            };
+        }
+        Iterator {
+            database: Arc::clone(&self.database),
+            current_hash: String::default(),
+        }
+    }
+
+    /// 寻找目标Transaction
+    ///
+    /// # Arguments
+    ///
+    /// - `&mut self` (`undefined`)
+    /// - `tx_id` (`&Vec<u8>`) - tx_id
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Transaction>` - 事务, 不在主链上时为None
+    pub async fn find_transaction(&self, tx_id: &[u8]) -> Option<Transaction> {
+        let database = self.database.read().await;
+        Blockchain::read_transaction(&*database, tx_id)
+    }
+
+    /// 在database的主链上查找交易
+    fn read_transaction(database: &dyn Storage, tx_id: &[u8]) -> Option<Transaction> {
+        // 开启交易索引时直接定位, 否则从链顶向前遍历
+        if Blockchain::tx_index_enabled(database) {
+            let bytes = database
+                .get(Blockchain::tx_index_key(tx_id).as_bytes())
+                .ok()
+                .flatten()?;
+            let (location, _): (TxLocation, usize) =
+                bincode::decode_from_slice(&bytes, config::standard()).unwrap();
+            let block = Blockchain::read_block(database, &location.block_hash)?;
+            return block.transactions.into_iter().nth(location.position);
+        }
+
+        let mut hash = database
+            .get(LATEST_HASH_KEY.as_bytes())
+            .ok()
+            .flatten()
+            .map(hex::encode)?;
+        while !hash.is_empty() {
+            let block = Blockchain::read_block(database, &hash)?;
+            if let Some(tx) = block.transactions.into_iter().find(|tx| tx.id == tx_id) {
+                return Some(tx);
+            }
+            hash = block.header.prev_hash;
+        }
+
+        None
+    }
+
+    /// 给Tx签名
+    ///
+    /// # Arguments
+    ///
+    /// - `tx` (`&Transaction`) - 待签名的Tx
+    /// - `priv_key` (`&SigningKey`) - 签名的私钥
+    ///
+    /// # Returns
+    ///
+    /// - `Result<(), SignError>` - Input关联的Tx不在主链上时签名失败
+    pub async fn sign_transaction(
+        &self,
+        tx_to_sign: &mut Transaction,
+        priv_key: &mut SigningKey,
+    ) -> Result<(), SignError> {
+        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+        for input in &tx_to_sign.inputs {
+            // 缺失的Tx由Transaction::sign报错
+            if let Some(tx) = self.find_transaction(&input.tx_id).await {
+                prev_txs.insert(hex::encode(&input.tx_id), tx);
+            }
+        }
+
+        tx_to_sign.sign(priv_key, prev_txs)
+    }
+
+    /// 校验某个Tx
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 是否通过校验
+    ///
+    pub async fn verify_transaction(&self, tx_to_verify: &Transaction) -> bool {
+        let database = self.database.read().await;
+        Blockchain::check_signatures(&*database, tx_to_verify)
+    }
+
+    /// 按database主链上的前序交易校验签名
+    fn check_signatures(database: &dyn Storage, tx_to_verify: &Transaction) -> bool {
+        let mut prev_txs: HashMap<String, Transaction> = HashMap::default();
+
+        for input in &tx_to_verify.inputs {
+            let Some(tx) = Blockchain::read_transaction(database, &input.tx_id) else {
+                return false;
+            };
+            prev_txs.insert(hex::encode(&input.tx_id), tx);
+        }
+
+        tx_to_verify.verity(prev_txs)
+    }
+}
+
+pub struct Iterator {
+    pub database: Arc<RwLock<dyn Storage>>,
+    pub current_hash: String,
+}
+
+impl Iterator {
+    pub async fn next(&mut self) -> Option<Block> {
+        if &self.current_hash == "" {
+            return None;
+        }
+        let database = self.database.write().await;
+
+        let encoded_data = database
+            .get(self.current_hash.as_bytes())
+            .ok()
+            .flatten()
             .expect(&format!("Hash {} has no data in DB!", &self.current_hash));
 
         let (block, _len): (Block, usize) =
@@ -396,8 +1553,281 @@ impl Iterator {
                 &self.current_hash
             ));
 
-        let prev_hash = block.prev_hash.clone();
+        let prev_hash = block.header.prev_hash.clone();
         self.current_hash = prev_hash;
         Some(block)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::MemoryStorage,
+        tx::{COINBASE_MATURITY, TxInput, TxOutput},
+        wallet::Wallet,
+    };
+
+    const EMISSION: EmissionSchedule = EmissionSchedule {
+        initial_subsidy: 100,
+        halving_interval: 210,
+        tail_emission: None,
+    };
+
+    async fn new_chain(to: &Wallet) -> (Blockchain, Block) {
+        let chain = Blockchain::create(MemoryStorage::new(), to.address(), EMISSION).await;
+        let genesis = chain.get_block_by_height(1).await.unwrap();
+        (chain, genesis)
+    }
+
+    /// 在parent之上挖出一个区块, coinbase领取全部挖矿奖励
+    async fn mine_on(
+        chain: &Blockchain,
+        parent: &Block,
+        to: &Wallet,
+        txs: Vec<Transaction>,
+    ) -> Block {
+        let height = parent.height + 1;
+        let bits = chain.next_difficulty(&parent.header_entry()).await;
+        let coinbase = Transaction::coinbase_tx(to.address(), height, EMISSION.subsidy(height));
+        let mut transactions = vec![coinbase];
+        transactions.extend(txs);
+        Block::create_block(parent.hash.clone(), transactions, height, bits)
+    }
+
+    /// 把prev_tx的第0个Output全部转给to
+    fn spend(prev_tx: &Transaction, owner: &mut Wallet, to: &Wallet) -> Transaction {
+        let input = TxInput::new(prev_tx.id.clone(), 0, owner.pub_key.clone(), vec![]);
+        let mut tx = Transaction {
+            id: vec![],
+            inputs: vec![input],
+            outputs: vec![TxOutput::new(prev_tx.outputs[0].amount, to.address())],
+        };
+        tx.id = tx.hash();
+
+        let prev_txs = HashMap::from([(hex::encode(&prev_tx.id), prev_tx.clone())]);
+        tx.sign(&mut owner.priv_key, prev_txs).unwrap();
+        tx
+    }
+
+    async fn tip(chain: &Blockchain) -> String {
+        let database = chain.database.read().await;
+        hex::encode(database.get(LATEST_HASH_KEY.as_bytes()).unwrap().unwrap())
+    }
+
+    async fn has_utxo(chain: &Blockchain, tx: &Transaction) -> bool {
+        let database = chain.database.read().await;
+        UTXOSet::read_output(&*database, &tx.id, 0).is_some()
+    }
+
+    async fn utxo_entries(chain: &Blockchain) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let database = chain.database.read().await;
+        database.scan_prefix(b"utxo-").map(Result::unwrap).collect()
+    }
+
+    #[tokio::test]
+    async fn heavier_branch_becomes_main_chain() {
+        let (alice, bob) = (Wallet::new(), Wallet::new());
+        let (chain, genesis) = new_chain(&alice).await;
+
+        let a1 = mine_on(&chain, &genesis, &alice, vec![]).await;
+        chain.add_block(a1.clone()).await.unwrap();
+        // 工作量相同的分支不替换主链
+        let b1 = mine_on(&chain, &genesis, &bob, vec![]).await;
+        chain.add_block(b1.clone()).await.unwrap();
+        assert_eq!(tip(&chain).await, a1.hash);
+        assert!(has_utxo(&chain, &a1.transactions[0]).await);
+        assert!(!has_utxo(&chain, &b1.transactions[0]).await);
+
+        let b2 = mine_on(&chain, &b1, &bob, vec![]).await;
+        chain.add_block(b2.clone()).await.unwrap();
+        assert_eq!(tip(&chain).await, b2.hash);
+        assert_eq!(chain.get_height().await, 3);
+        assert_eq!(chain.get_block_by_height(2).await.unwrap().hash, b1.hash);
+        assert!(!has_utxo(&chain, &a1.transactions[0]).await);
+        assert!(has_utxo(&chain, &b1.transactions[0]).await);
+        assert!(has_utxo(&chain, &b2.transactions[0]).await);
+        // 被替换的区块仍然保存在侧链上
+        assert!(chain.has_block(&a1.hash).await);
+    }
+
+    #[tokio::test]
+    async fn invalid_branch_does_not_replace_main_chain() {
+        let (alice, bob) = (Wallet::new(), Wallet::new());
+        let (chain, genesis) = new_chain(&alice).await;
+        let a1 = mine_on(&chain, &genesis, &alice, vec![]).await;
+        chain.add_block(a1.clone()).await.unwrap();
+        let before = utxo_entries(&chain).await;
+
+        // 侧链区块的交易在重组时才校验
+        let coinbase = Transaction::coinbase_tx(bob.address(), 2, EMISSION.subsidy(2) + 1);
+        let b1 = Block::create_block(genesis.hash.clone(), vec![coinbase], 2, genesis.header.bits);
+        chain.add_block(b1.clone()).await.unwrap();
+        let b2 = mine_on(&chain, &b1, &bob, vec![]).await;
+
+        let result = chain.add_block(b2.clone()).await;
+        assert!(matches!(result, Err(BlockValidationError::CoinbaseTooLarge { .. })));
+        assert_eq!(tip(&chain).await, a1.hash);
+        assert_eq!(utxo_entries(&chain).await, before);
+        assert!(!chain.has_block(&b1.hash).await);
+        assert!(!chain.has_block(&b2.hash).await);
+    }
+
+    #[tokio::test]
+    async fn validate_block_rejects_invalid_blocks() {
+        let alice = Wallet::new();
+        let (chain, genesis) = new_chain(&alice).await;
+        let valid = mine_on(&chain, &genesis, &alice, vec![]).await;
+        assert!(chain.validate_block(&valid).await.is_ok());
+
+        let mut block = valid.clone();
+        block.header.nonce += 1;
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::HashMismatch)));
+
+        let mut block = valid.clone();
+        block.transactions.push(Transaction::coinbase_tx(alice.address(), 2, 1));
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::MerkleRootMismatch)));
+
+        let coinbases = vec![
+            Transaction::coinbase_tx(alice.address(), 2, 1),
+            Transaction::coinbase_tx(alice.address(), 2, 1),
+        ];
+        let block = Block::create_block(genesis.hash.clone(), coinbases, 2, genesis.header.bits);
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::MultipleCoinbase)));
+
+        let coinbase = Transaction::coinbase_tx(alice.address(), 3, 1);
+        let block = Block::create_block(genesis.hash.clone(), vec![coinbase], 2, genesis.header.bits);
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::BadCoinbaseHeight)));
+
+        let coinbase = Transaction::coinbase_tx(alice.address(), 3, 1);
+        let block = Block::create_block(genesis.hash.clone(), vec![coinbase], 3, genesis.header.bits);
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::BadHeight { expected: 2, actual: 3 })));
+
+        let coinbase = Transaction::coinbase_tx(alice.address(), 2, 1);
+        let bits = genesis.header.bits + 1;
+        let block = Block::create_block(genesis.hash.clone(), vec![coinbase], 2, bits);
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::BadDifficulty { .. })));
+
+        let coinbase = Transaction::coinbase_tx(alice.address(), 2, 1);
+        let block = Block::create_block(hex::encode([7u8; 32]), vec![coinbase], 2, 1);
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::UnknownParent)));
+
+        let coinbase = Transaction::coinbase_tx(alice.address(), 1, 1);
+        let block = Block::genesis(coinbase);
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::UnexpectedGenesis)));
+
+        let coinbase = Transaction::coinbase_tx(alice.address(), 2, 1);
+        let mut block = Block::new_template(genesis.hash.clone(), vec![coinbase], 2, 1);
+        block.header.timestamp = genesis.header.timestamp - 1;
+        assert!(block.mine(&AtomicBool::new(false)));
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::TimestampTooOld)));
+    }
+
+    #[tokio::test]
+    async fn validate_block_rejects_oversized_block() {
+        let alice = Wallet::new();
+        let (chain, genesis) = new_chain(&alice).await;
+
+        // 输出足够多的coinbase使区块超过MAX_BLOCK_SIZE
+        let mut coinbase = Transaction::coinbase_tx(alice.address(), 2, 1);
+        let output = coinbase.outputs[0].clone();
+        coinbase.outputs = vec![output; MAX_BLOCK_SIZE / 20];
+        coinbase.id = coinbase.hash();
+        let block = Block::create_block(genesis.hash.clone(), vec![coinbase], 2, 1);
+        let result = chain.validate_block(&block).await;
+        assert!(matches!(result, Err(BlockValidationError::TooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn coinbase_cannot_be_spent_before_maturity() {
+        let (mut alice, bob) = (Wallet::new(), Wallet::new());
+        let (chain, genesis) = new_chain(&alice).await;
+        let tx = spend(&genesis.transactions[0], &mut alice, &bob);
+
+        let result = chain.validate_transaction(&tx, 2).await;
+        assert!(matches!(result, Err(BlockValidationError::ImmatureCoinbase(_))));
+        let result = chain.validate_transaction(&tx, 1 + COINBASE_MATURITY).await;
+        assert_eq!(result.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn disconnecting_a_block_restores_the_utxo_set() {
+        let (mut alice, bob) = (Wallet::new(), Wallet::new());
+        let (chain, genesis) = new_chain(&alice).await;
+        let mut parent = genesis.clone();
+        for _ in 0..COINBASE_MATURITY {
+            let block = mine_on(&chain, &parent, &bob, vec![]).await;
+            chain.add_block(block.clone()).await.unwrap();
+            parent = block;
+        }
+        let tx = spend(&genesis.transactions[0], &mut alice, &bob);
+        let block = mine_on(&chain, &parent, &bob, vec![tx.clone()]).await;
+        let before = utxo_entries(&chain).await;
+
+        {
+            let database = chain.database.write().await;
+            let mut batch = WriteBatch::default();
+            Blockchain::connect_batch(&*database, &block, &mut batch);
+            database.apply_batch(batch).unwrap();
+        }
+        assert!(!has_utxo(&chain, &genesis.transactions[0]).await);
+        assert!(has_utxo(&chain, &tx).await);
+        assert_eq!(tip(&chain).await, block.hash);
+
+        {
+            let database = chain.database.write().await;
+            let mut batch = WriteBatch::default();
+            Blockchain::disconnect_batch(&*database, &block, &mut batch);
+            database.apply_batch(batch).unwrap();
+        }
+        assert_eq!(utxo_entries(&chain).await, before);
+        assert_eq!(tip(&chain).await, parent.hash);
+    }
+
+    #[tokio::test]
+    async fn pruning_drops_headers_that_cannot_overtake_main_chain() {
+        let (alice, bob) = (Wallet::new(), Wallet::new());
+        let (chain, genesis) = new_chain(&alice).await;
+        let a1 = mine_on(&chain, &genesis, &alice, vec![]).await;
+        chain.add_block(a1.clone()).await.unwrap();
+
+        // b1与主链顶端工作量相同, a2超过主链顶端
+        let b1 = mine_on(&chain, &genesis, &bob, vec![]).await;
+        let a2 = mine_on(&chain, &a1, &alice, vec![]).await;
+        let (accepted, rejected) = chain
+            .accept_headers(vec![b1.header.clone(), a2.header.clone()])
+            .await;
+        assert_eq!(accepted.len(), 2);
+        assert!(rejected.is_none());
+
+        assert_eq!(chain.prune_headers().await, 1);
+        assert!(chain.get_header(&b1.hash).await.is_none());
+        assert!(chain.get_header(&a2.hash).await.is_some());
+
+        chain.forget_headers(std::slice::from_ref(&a2.hash)).await;
+        assert!(chain.get_header(&a2.hash).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn required_difficulty_needs_known_parent() {
+        let alice = Wallet::new();
+        let (chain, genesis) = new_chain(&alice).await;
+        let block = mine_on(&chain, &genesis, &alice, vec![]).await;
+        let expected = chain.next_difficulty(&genesis.header_entry()).await;
+        assert_eq!(chain.required_difficulty(&block).await.unwrap(), expected);
+
+        let mut orphan = block.clone();
+        orphan.header.prev_hash = "00".repeat(32);
+        let result = chain.required_difficulty(&orphan).await;
+        assert!(matches!(result, Err(BlockValidationError::UnknownParent)));
+    }
+}
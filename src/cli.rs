@@ -1,14 +1,20 @@
-use std::{rc::Rc, sync::Arc};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use base58::FromBase58;
 use bytes::BytesMut;
 use clap::{Parser, ValueEnum};
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
 use tokio::net::TcpStream;
 use tokio_util::codec::Framed;
 
 use crate::{
-    blockchain::Blockchain, cli, network::{command::{Command, SendTxCmd}, LengthHeaderDelimiter, Server}, proof_of_work::ProofOfWork, transaction::Transaction, utxo::UTXOSet, wallet::{self, Wallet}, wallets::Wallets
+    address_index::AddressIndex, block::Block, blockchain::Blockchain, cli, network::{banlist::BanList, command::{decode_payload, Cmd, Command, GetPeerInfoCmd, Network, PeerInfoCmd, SendTxCmd, VersionCmd}, LengthHeaderDelimiter, Server}, proof_of_work::ProofOfWork, transaction::Transaction, utxo::UTXOSet, wallet::{self, Wallet}, wallets::Wallets
 };
 
 #[derive(Debug, Clone, ValueEnum, PartialEq)]
@@ -31,6 +37,18 @@ pub enum CliOperation {
     Rebuild,
     #[clap(rename_all = "kebab-case")]
     StartNode,
+    #[clap(rename_all = "kebab-case")]
+    TotalSupply,
+    #[clap(rename_all = "kebab-case")]
+    GetBlock,
+    #[clap(rename_all = "kebab-case")]
+    History,
+    #[clap(rename_all = "kebab-case")]
+    ListBans,
+    #[clap(rename_all = "kebab-case")]
+    ClearBans,
+    #[clap(rename_all = "kebab-case")]
+    Peers,
 }
 
 #[derive(Parser, Debug)]
@@ -50,6 +68,9 @@ pub struct CliParam {
     #[arg(long = "amount")]
     pub amount: Option<u128>,
 
+    #[arg(long = "fee", default_value_t = 0)]
+    pub fee: u128,
+
     #[arg(long = "miner-address")]
     pub miner_address: Option<String>,
 
@@ -58,6 +79,31 @@ pub struct CliParam {
 
     #[arg(long = "mine")]
     pub mine: Option<bool>,
+
+    #[arg(long = "height")]
+    pub height: Option<u128>,
+
+    #[arg(long = "txindex")]
+    pub txindex: Option<bool>,
+
+    #[arg(long = "addrindex")]
+    pub addrindex: Option<bool>,
+
+    // 节点监听地址, 默认为localhost:NODE_ID
+    #[arg(long = "listen")]
+    pub listen: Option<String>,
+
+    // 启动时连接的种子节点, 逗号分隔
+    #[arg(long = "seeds", value_delimiter = ',', default_value = "localhost:3000")]
+    pub seeds: Vec<String>,
+
+    // 节点所属的网络, 不同网络的节点不能互相连接
+    #[arg(long = "network", value_enum, default_value = "mainnet")]
+    pub network: Network,
+
+    // 要解除封禁的节点地址, 不指定时清空封禁列表
+    #[arg(long = "peer")]
+    pub peer: Option<String>,
 }
 
 impl CliParam {
@@ -66,6 +112,12 @@ impl CliParam {
         if self.operation == CliOperation::Send && (self.from.is_none() || self.to.is_none()) {
             panic!("[send] operation requires -from -to argument");
         }
+        if self.operation == CliOperation::GetBlock && self.height.is_none() {
+            panic!("[get-block] operation requires --height argument");
+        }
+        if self.operation == CliOperation::History && self.address.is_none() {
+            panic!("[history] operation requires --address argument");
+        }
     }
 }
 
@@ -93,6 +145,12 @@ impl CommandLine {
             CliOperation::PrintUsage => self.print_usage(),
             CliOperation::Rebuild => self.rebuild().await,
             CliOperation::StartNode => self.start_node().await,
+            CliOperation::TotalSupply => self.total_supply().await,
+            CliOperation::GetBlock => self.get_block().await,
+            CliOperation::History => self.history().await,
+            CliOperation::ListBans => self.list_bans(),
+            CliOperation::ClearBans => self.clear_bans(),
+            CliOperation::Peers => self.peers().await,
         }
     }
 
@@ -100,17 +158,35 @@ impl CommandLine {
         println!("Usage:");
         println!("get-balance -address ADDRESS - Get the balance for an address");
         println!(
-            "create-chain --node-id NODE_ID --address ADDRESS - Create a blockchain and send genesis reward to address."
+            "create-chain --node-id NODE_ID --address ADDRESS [--txindex true] [--addrindex true] [--network mainnet|testnet] - Create a blockchain of the network and send genesis reward to address. Maintain a transaction or address index if the flag is set."
         );
         println!("print-chain --node-id NODE_ID - Prints the blocks in the chain");
         println!(
-            "send --node-id NODE_ID --from FROM --to TO --amount AMOUNT --mine - Send amount of coins. Then -mine flag is set, mine off of this node."
+            "get-block --node-id NODE_ID --height HEIGHT - Prints the main chain block at HEIGHT"
+        );
+        println!(
+            "history --node-id NODE_ID --address ADDRESS - Lists the transactions of ADDRESS, requires the address index"
+        );
+        println!(
+            "send --node-id NODE_ID --from FROM --to TO --amount AMOUNT [--fee FEE] --mine [--seeds SEEDS] [--network mainnet|testnet] - Send amount of coins and pay FEE to the miner. Then -mine flag is set, mine off of this node, otherwise submit to the first of SEEDS."
         );
         println!("create-wallet --node-id NODE_ID - Creates a new Wallet");
         println!("list-address --node-id NODE_ID - Lists the addresses in out wallet file");
-        println!("rebuild --node-id NODE_ID - Rebuilds the UTXO set.");
         println!(
-            "startnode --node-id NODE_ID --miner-address ADDRESS - Start a node with ID specified in NODE_ID"
+            "rebuild --node-id NODE_ID [--txindex true|false] [--addrindex true|false] - Rebuilds the UTXO set, and builds or drops the transaction or address index if the flag is given."
+        );
+        println!(
+            "total-supply --node-id NODE_ID [--height HEIGHT] [--network mainnet|testnet] - Prints the coins issued up to HEIGHT under the network's emission schedule, defaults to the chain height"
+        );
+        println!(
+            "start-node --node-id NODE_ID [--miner-address ADDRESS] [--listen ADDR] [--seeds ADDR,ADDR] [--network mainnet|testnet] - Start a node with ID specified in NODE_ID listening on ADDR (default localhost:NODE_ID), discover peers from SEEDS (default localhost:3000) on the given network (default mainnet), mine pending transactions to ADDRESS if given"
+        );
+        println!(
+            "peers --node-id NODE_ID [--listen ADDR] - Lists the peers connected to the node listening on ADDR (default localhost:NODE_ID) with their latency"
+        );
+        println!("list-bans --node-id NODE_ID - Lists the banned peer IPs and when their bans expire");
+        println!(
+            "clear-bans --node-id NODE_ID [--peer IP] - Lifts the ban on IP, or all bans if no peer is given. A running node picks the change up within about 10 seconds"
         );
     }
 
@@ -121,12 +197,37 @@ impl CommandLine {
                 panic!("Invalid miner-address: {}", miner_address); 
             }
         }
-        // start server
-        let miner_address: String = self.cli_param.miner_address.take().unwrap();
-        let mut server = Server::new(node_id, miner_address); 
+        // start server, mine only if miner-address is given
+        let miner_address = self.cli_param.miner_address.take();
+        let listen = self
+            .cli_param
+            .listen
+            .take()
+            .unwrap_or_else(|| format!("localhost:{}", node_id));
+        let seeds = std::mem::take(&mut self.cli_param.seeds);
+        let network = self.cli_param.network;
+        let mut server = Server::new(node_id, network, listen, seeds, miner_address);
         server.start_node().await;
     }
 
+    async fn total_supply(&self) {
+        let emission = self.cli_param.network.emission_schedule();
+        let height = match self.cli_param.height {
+            Some(height) => height,
+            None => {
+                let blockchain = Blockchain::continue_chain(self.cli_param.node_id, emission).await;
+                blockchain.get_height().await
+            }
+        };
+
+        println!(
+            "Total supply at height {}: {} coins, block subsidy: {}",
+            height,
+            emission.total_supply(height),
+            emission.subsidy(height)
+        );
+    }
+
     fn create_wallet(&mut self) {
         let node_id = self.cli_param.node_id;
         let mut wallets = Wallets::new(node_id);
@@ -135,6 +236,86 @@ impl CommandLine {
         println!("Succeed creating wallet: {}\n", address);
     }
 
+    fn list_bans(&self) {
+        let ban_list = BanList::load(self.cli_param.node_id);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let bans = ban_list.list();
+        if bans.is_empty() {
+            println!("No banned peers");
+        }
+        for (addr, until) in bans {
+            println!("{} banned for another {}s", addr, until.saturating_sub(now));
+        }
+    }
+
+    fn clear_bans(&mut self) {
+        let mut ban_list = BanList::load(self.cli_param.node_id);
+        match self.cli_param.peer.take() {
+            Some(peer) => {
+                if ban_list.unban(&peer) {
+                    println!("Unbanned {}", peer);
+                } else {
+                    println!("{} is not banned", peer);
+                }
+            }
+            None => {
+                ban_list.clear();
+                println!("Cleared all bans");
+            }
+        }
+        ban_list.save();
+    }
+
+    async fn peers(&self) {
+        let node_id = self.cli_param.node_id;
+        let addr = self
+            .cli_param
+            .listen
+            .clone()
+            .unwrap_or_else(|| format!("localhost:{}", node_id));
+        let cli_addr = Arc::new(format!("cli:{}", node_id));
+        let mut framed = connect_node(&addr, self.cli_param.network, Arc::clone(&cli_addr)).await;
+
+        let cmd = GetPeerInfoCmd::new(cli_addr);
+        framed.send(BytesMut::from(&cmd.serialize()[..])).await.unwrap();
+
+        // 跳过节点的version等消息, 直到收到回复
+        let reply = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(package)) = framed.next().await {
+                if matches!(Cmd::decode(package[5..7].try_into().unwrap()), Cmd::PeerInfo) {
+                    return decode_payload::<PeerInfoCmd>(&package).ok();
+                }
+            }
+            None
+        })
+        .await;
+        let Ok(Some(reply)) = reply else {
+            panic!("No peer info from {}", addr);
+        };
+
+        if reply.peers.is_empty() {
+            println!("No connected peers");
+        }
+        for peer in reply.peers {
+            let latency = match peer.latency_ms {
+                Some(latency) => format!("{}ms", latency),
+                None => String::from("-"),
+            };
+            println!(
+                "{} {} {}, version {}, height {}, latency {}",
+                peer.addr,
+                if peer.outbound { "outbound" } else { "inbound" },
+                peer.user_agent,
+                peer.version,
+                peer.start_height,
+                latency
+            );
+        }
+    }
+
     fn get_all_address(&mut self) {
         let node_id = self.cli_param.node_id;
         let wallets = Wallets::new(node_id);
@@ -151,16 +332,30 @@ impl CommandLine {
 
         let node_id = self.cli_param.node_id;
 
-        let blockchain = Rc::new(Blockchain::init(node_id, address).await);
-        let utxo_set = UTXOSet::new(blockchain);
-        utxo_set.rebuild().await;
+        let emission = self.cli_param.network.emission_schedule();
+        let blockchain = Blockchain::init(node_id, address, emission).await;
+        if self.cli_param.txindex == Some(true) {
+            blockchain.set_tx_index(true).await;
+        }
+        if self.cli_param.addrindex == Some(true) {
+            AddressIndex::set_enabled(&blockchain, true).await;
+        }
         println!("Created blockchain!");
     }
 
     async fn rebuild(&self) {
         let node_id = self.cli_param.node_id;
 
-        let blockchain = Rc::new(Blockchain::continue_chain(node_id).await);
+        let emission = self.cli_param.network.emission_schedule();
+        let blockchain = Rc::new(Blockchain::continue_chain(node_id, emission).await);
+        if let Some(enabled) = self.cli_param.txindex {
+            blockchain.set_tx_index(enabled).await;
+            println!("Transaction index {}!", if enabled { "built" } else { "dropped" });
+        }
+        if let Some(enabled) = self.cli_param.addrindex {
+            AddressIndex::set_enabled(&blockchain, enabled).await;
+            println!("Address index {}!", if enabled { "built" } else { "dropped" });
+        }
         let utxo_set = UTXOSet::new(blockchain);
         utxo_set.rebuild().await;
         println!("UTXO set rebuild!");
@@ -169,15 +364,12 @@ impl CommandLine {
     async fn print_chain(&self) {
 
         let node_id = self.cli_param.node_id;
-        let blockchain = Blockchain::continue_chain(node_id).await;
+        let emission = self.cli_param.network.emission_schedule();
+        let blockchain = Blockchain::continue_chain(node_id, emission).await;
         let mut iter = blockchain.iterator().await;
         loop {
             if let Some(block) = iter.next().await {
-                println!("Height: {}", block.height);
-                println!("Prev hash: {:?}", &block.prev_hash);
-                println!("Hash: {:?}", &block.hash);
-                let pow = ProofOfWork::new(&block);
-                println!("Pow: {:?}\n\n", pow.validate());
+                CommandLine::print_block(&blockchain, &block).await;
             } else {
                 println!("---------------------------------------\n");
                 println!("Iterate all block!");
@@ -186,9 +378,85 @@ impl CommandLine {
         }
     }
 
+    async fn get_block(&self) {
+        let height = self.cli_param.height.unwrap();
+        let emission = self.cli_param.network.emission_schedule();
+        let blockchain = Blockchain::continue_chain(self.cli_param.node_id, emission).await;
+        match blockchain.get_block_by_height(height).await {
+            Some(block) => CommandLine::print_block(&blockchain, &block).await,
+            None => println!("No block at height {} on the main chain", height),
+        }
+    }
+
+    async fn history(&mut self) {
+        let address = self.cli_param.address.take().unwrap();
+        if !Wallet::validate_address(&address) {
+            panic!("Address: {} is not a valid address", address);
+        }
+        let addr_base58 = address.from_base58().unwrap();
+        let pubkey_hash = &addr_base58[1..addr_base58.len() - wallet::CHECK_SUM_LENGTH];
+
+        let emission = self.cli_param.network.emission_schedule();
+        let blockchain = Blockchain::continue_chain(self.cli_param.node_id, emission).await;
+        let Some(history) = AddressIndex::history(&blockchain, pubkey_hash).await else {
+            println!("Address index is disabled, run rebuild --addrindex true first!");
+            return;
+        };
+
+        let tip_height = blockchain.get_height().await;
+        println!("Address {} has {} transaction(s):", &address, history.len());
+        for record in history {
+            let counterparties: Vec<String> = record
+                .counterparties
+                .iter()
+                .map(|pub_key_hash| Wallet::pub_key_hash_to_address(pub_key_hash))
+                .collect();
+            let net = if record.received >= record.sent {
+                format!("+{}", record.received - record.sent)
+            } else {
+                format!("-{}", record.sent - record.received)
+            };
+
+            println!("Tx: {}", hex::encode(&record.tx_id));
+            println!(
+                "Height: {}, confirmations: {}",
+                record.height,
+                tip_height + 1 - record.height
+            );
+            println!(
+                "Received: {}, sent: {}, net: {}",
+                record.received, record.sent, net
+            );
+            if record.coinbase {
+                println!("Counterparties: coinbase\n");
+            } else if counterparties.is_empty() {
+                println!("Counterparties: none\n");
+            } else {
+                println!("Counterparties: {}\n", counterparties.join(", "));
+            }
+        }
+    }
+
+    async fn print_block(blockchain: &Blockchain, block: &Block) {
+        println!("Height: {}", block.height);
+        println!("Prev hash: {:?}", &block.header.prev_hash);
+        println!("Merkle root: {:?}", &block.header.merkle_root);
+        println!("Timestamp: {}", block.header.timestamp);
+        println!("Hash: {:?}", &block.hash);
+        println!("Difficulty: {}", block.header.bits);
+        match blockchain.required_difficulty(block).await {
+            Ok(difficulty) => {
+                let pow = ProofOfWork::new(&block.header);
+                println!("Pow: {:?}\n\n", pow.validate(difficulty));
+            }
+            Err(err) => println!("Pow: {}\n\n", err),
+        }
+    }
+
     async fn get_balance(&mut self) {
         let node_id = self.cli_param.node_id;
-        let blockchain = Rc::new(Blockchain::continue_chain(node_id).await);
+        let emission = self.cli_param.network.emission_schedule();
+        let blockchain = Rc::new(Blockchain::continue_chain(node_id, emission).await);
 
         let address = self.cli_param.address.take().unwrap();
         if !Wallet::validate_address(&address) {
@@ -230,7 +498,8 @@ impl CommandLine {
 
         let node_id = cli_param.node_id;
 
-        let blockchain = Rc::new(Blockchain::continue_chain(node_id).await);
+        let emission = cli_param.network.emission_schedule();
+        let blockchain = Rc::new(Blockchain::continue_chain(node_id, emission).await);
         let mut utxo_set = UTXOSet::new(Rc::clone(&blockchain));
 
         let node_id = cli_param.node_id;
@@ -241,31 +510,68 @@ impl CommandLine {
         // 获取转账钱包记录
         if let Some(wallet_from) = wallets.get_wallet_mut(&addr_from) {
 
+            let fee = cli_param.fee;
             let tx = Transaction::new(
                 wallet_from,
                 &cli_param.to.take().unwrap(),
                 cli_param.amount.take().unwrap(),
+                fee,
                 &mut utxo_set,
-            ).await;
-
-            if self.cli_param.mine.unwrap() {
-                let new_block = blockchain.mine_block(vec![tx]).await;
-                // 更新UTXO set
-                utxo_set.update(&new_block).await;
+            )
+            .await
+            .unwrap_or_else(|err| panic!("Failed to sign transaction: {}", err));
+
+            if cli_param.mine.unwrap() {
+                let height = blockchain.get_height().await + 1;
+                let reward = blockchain.emission.subsidy(height) + fee;
+                let coinbase_tx = Transaction::coinbase_tx(addr_from.clone(), height, reward);
+                // 区块与UTXO set一并更新
+                blockchain.mine_block(vec![coinbase_tx, tx]).await;
                 println!("Succeed sending coin!");
             } else {
-                // todo: send tcp tx to center node
-                let tcp_stream = TcpStream::connect("localhost:3000").await.unwrap();
-                let mut framed = Framed::new(tcp_stream, LengthHeaderDelimiter {});
-
-                let cmd = SendTxCmd::new(Arc::new(String::from("localhost:3000")), tx);
-                let mut payload = BytesMut::new();
-                payload.extend_from_slice(&cmd.serialize());
-                framed.send(payload).await.unwrap();
-                println!("Sent Tx to center node!");
+                // 交易发给第一个种子节点
+                let seed = cli_param.seeds.first().expect("--seeds is empty");
+                let cli_addr = Arc::new(format!("cli:{}", node_id));
+                let mut framed =
+                    connect_node(seed, cli_param.network, Arc::clone(&cli_addr)).await;
+
+                let cmd = SendTxCmd::new(cli_addr, tx);
+                framed.send(BytesMut::from(&cmd.serialize()[..])).await.unwrap();
+                println!("Sent Tx to {}!", seed);
             }
         } else {
             panic!("不存在from钱包: {}", addr_from)
         }
     }
 }
+
+/// 连接节点并以不提供服务的身份完成握手, 之后即可发送其他消息
+///
+/// # Arguments
+///
+/// - `addr` (`&str`) - 节点地址
+/// - `network` (`Network`) - 节点所属的网络
+/// - `cli_addr` (`Arc<String>`) - 命令行在节点中登记的地址
+///
+/// # Returns
+///
+/// - `Framed<TcpStream, LengthHeaderDelimiter>` - 与节点的连接
+async fn connect_node(
+    addr: &str,
+    network: Network,
+    cli_addr: Arc<String>,
+) -> Framed<TcpStream, LengthHeaderDelimiter> {
+    let tcp_stream = TcpStream::connect(addr).await.unwrap();
+    let mut framed = Framed::new(tcp_stream, LengthHeaderDelimiter::new(network));
+
+    // 节点只处理握手后的消息, 命令行不提供任何服务
+    let mut version_cmd =
+        VersionCmd::new(cli_addr, 0, RandomState::new().build_hasher().finish());
+    version_cmd.services = 0;
+    framed
+        .send(BytesMut::from(&version_cmd.serialize()[..]))
+        .await
+        .unwrap();
+
+    framed
+}
@@ -0,0 +1,142 @@
+/// 挖矿奖励的发行计划
+///
+/// # Fields
+///
+/// - `initial_subsidy` (`u128`) - 创世块起的初始奖励
+/// - `halving_interval` (`u128`) - 每隔多少个区块奖励减半, 0表示不减半
+/// - `tail_emission` (`Option<u128>`) - 减半后的最低奖励, None表示最终减到0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmissionSchedule {
+    pub initial_subsidy: u128,
+    pub halving_interval: u128,
+    pub tail_emission: Option<u128>,
+}
+
+impl EmissionSchedule {
+    /// 计算某高度区块的挖矿奖励, 创世块高度为1
+    ///
+    /// # Arguments
+    ///
+    /// - `height` (`u128`) - 区块高度
+    ///
+    /// # Returns
+    ///
+    /// - `u128` - 奖励
+    pub fn subsidy(&self, height: u128) -> u128 {
+        if height == 0 {
+            return 0;
+        }
+
+        let halvings = match self.halving_interval {
+            0 => 0,
+            interval => (height - 1) / interval,
+        };
+        let subsidy = if halvings >= u128::BITS as u128 {
+            0
+        } else {
+            self.initial_subsidy >> halvings
+        };
+
+        match self.tail_emission {
+            Some(tail) => subsidy.max(tail),
+            None => subsidy,
+        }
+    }
+
+    /// 计算截至某高度(含)为止发行的总量
+    ///
+    /// # Arguments
+    ///
+    /// - `height` (`u128`) - 区块高度
+    ///
+    /// # Returns
+    ///
+    /// - `u128` - 总发行量
+    pub fn total_supply(&self, height: u128) -> u128 {
+        let interval = match self.halving_interval {
+            0 => height.max(1),
+            interval => interval,
+        };
+
+        // 按减半周期分段累加
+        let mut total = 0u128;
+        let mut start = 1u128;
+        while start <= height {
+            let subsidy = self.subsidy(start);
+            if subsidy == 0 {
+                break;
+            }
+            // 奖励已降到尾部发行量, 之后每个区块都相同
+            if Some(subsidy) == self.tail_emission {
+                let blocks = height - start + 1;
+                total = total.saturating_add(subsidy.saturating_mul(blocks));
+                break;
+            }
+
+            let end = start.saturating_add(interval - 1).min(height);
+            let blocks = end - start + 1;
+            total = total.saturating_add(subsidy.saturating_mul(blocks));
+            start = end + 1;
+        }
+
+        total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALVING: EmissionSchedule = EmissionSchedule {
+        initial_subsidy: 100,
+        halving_interval: 10,
+        tail_emission: None,
+    };
+    const TAIL: EmissionSchedule = EmissionSchedule {
+        initial_subsidy: 100,
+        halving_interval: 10,
+        tail_emission: Some(10),
+    };
+
+    #[test]
+    fn subsidy_halves_every_interval() {
+        assert_eq!(HALVING.subsidy(0), 0);
+        assert_eq!(HALVING.subsidy(1), 100);
+        assert_eq!(HALVING.subsidy(10), 100);
+        assert_eq!(HALVING.subsidy(11), 50);
+        assert_eq!(HALVING.subsidy(21), 25);
+        // 减半次数超过位数时为0
+        assert_eq!(HALVING.subsidy(10 * 200), 0);
+    }
+
+    #[test]
+    fn subsidy_stops_at_tail_emission() {
+        assert_eq!(TAIL.subsidy(31), 12);
+        assert_eq!(TAIL.subsidy(41), 10);
+        assert_eq!(TAIL.subsidy(10 * 200), 10);
+    }
+
+    #[test]
+    fn subsidy_without_halving_is_constant() {
+        let flat = EmissionSchedule {
+            initial_subsidy: 7,
+            halving_interval: 0,
+            tail_emission: None,
+        };
+        assert_eq!(flat.subsidy(1), 7);
+        assert_eq!(flat.subsidy(1_000_000), 7);
+        assert_eq!(flat.total_supply(1000), 7000);
+    }
+
+    #[test]
+    fn total_supply_matches_summed_subsidies() {
+        for schedule in [HALVING, TAIL] {
+            for height in [0, 1, 10, 11, 25, 100, 1000] {
+                let expected: u128 = (1..=height).map(|h| schedule.subsidy(h)).sum();
+                assert_eq!(schedule.total_supply(height), expected, "height {}", height);
+            }
+        }
+        // 不减到0时总量有上限
+        assert_eq!(HALVING.total_supply(10_000), HALVING.total_supply(1000));
+    }
+}
@@ -1,10 +1,18 @@
 #![allow(dead_code)]
+mod address_index;
 mod block;
 mod blockchain;
 mod cli;
+mod emission;
+mod mempool;
 mod merkle;
+mod miner;
 mod network;
+mod orphan;
 mod proof_of_work;
+mod storage;
+#[cfg(test)]
+mod test_support;
 mod transaction;
 mod tx;
 mod utxo;
@@ -58,4 +66,6 @@ async fn main() {
     let _exit_hook = AtExitMonitor;
     let mut command_line = CommandLine::new();
     command_line.run().await;
+    // 退出前持久化数据库
+    run_exit_callbacks().await;
 }
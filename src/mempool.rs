@@ -0,0 +1,276 @@
+use std::{collections::HashMap, fmt::Display};
+
+use bincode::config;
+
+use crate::{
+    blockchain::{BlockValidationError, Blockchain},
+    transaction::Transaction,
+    utxo::UTXOSet,
+};
+
+// 交易池最多容纳的交易数
+pub const MAX_MEMPOOL_TXS: usize = 5000;
+// 交易池中交易编码后的总字节数上限
+pub const MAX_MEMPOOL_BYTES: usize = 5 * 1024 * 1024;
+// 单笔交易编码后的字节数上限, 超过的交易不进入交易池也不转发
+pub const MAX_TX_SIZE: usize = 100 * 1024;
+
+/// 交易未能进入交易池的原因
+#[derive(Debug)]
+pub enum MempoolError {
+    AlreadyKnown,
+    Coinbase,
+    // 与交易池中某笔交易花费了同一个Output
+    Conflict(String),
+    // 交易池已满, 且该交易的费率不高于池中最低费率
+    Full,
+    TooLarge(usize),
+    Invalid(BlockValidationError),
+}
+
+impl Display for MempoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown => f.write_str("transaction is already in the pool"),
+            MempoolError::Coinbase => f.write_str("coinbase cannot be relayed"),
+            MempoolError::Conflict(tx_id) => {
+                write!(f, "conflicts with pending transaction {}", tx_id)
+            }
+            MempoolError::Full => f.write_str("mempool is full and the fee rate is too low"),
+            MempoolError::TooLarge(size) => {
+                write!(f, "transaction is {} bytes, only {} allowed", size, MAX_TX_SIZE)
+            }
+            MempoolError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// 交易池中的一笔交易
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: u128,
+    // 编码后的字节数, 用于计算费率
+    pub size: usize,
+}
+
+impl MempoolEntry {
+    /// 每字节手续费, 放大1000倍避免整数除法丢失精度
+    pub fn fee_rate(&self) -> u128 {
+        self.fee * 1000 / self.size.max(1) as u128
+    }
+}
+
+/// 交易池, 保存已校验但尚未被打包的交易
+#[derive(Default)]
+pub struct Mempool {
+    // tx_id -> MempoolEntry
+    txs: HashMap<String, MempoolEntry>,
+    // (tx_id, out_idx) -> 花费该Output的待确认tx_id
+    spends: HashMap<(String, usize), String>,
+    // 所有交易编码后的字节数之和
+    total_size: usize,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 校验并接收一笔交易
+    ///
+    /// # Arguments
+    ///
+    /// - `tx` (`Transaction`) - 待接收的交易
+    /// - `blockchain` (`&Blockchain`) - 主链
+    ///
+    /// # Returns
+    ///
+    /// - `Result<(), MempoolError>` - 交易被拒绝的原因
+    pub async fn accept(
+        &mut self,
+        tx: Transaction,
+        blockchain: &Blockchain,
+    ) -> Result<(), MempoolError> {
+        let tx_id = hex::encode(&tx.id);
+        if self.txs.contains_key(&tx_id) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        if tx.is_coinbase() {
+            return Err(MempoolError::Coinbase);
+        }
+        let size = bincode::encode_to_vec(&tx, config::standard()).unwrap().len();
+        if size > MAX_TX_SIZE {
+            return Err(MempoolError::TooLarge(size));
+        }
+        if tx.id != tx.hash() {
+            return Err(MempoolError::Invalid(BlockValidationError::TxIdMismatch(
+                tx_id,
+            )));
+        }
+
+        // 冲突检测, 同一笔交易内重复花费也视为冲突
+        let mut outpoints = vec![];
+        for input in &tx.inputs {
+            let outpoint = (hex::encode(&input.tx_id), input.out_idx);
+            if let Some(spender) = self.spends.get(&outpoint) {
+                return Err(MempoolError::Conflict(spender.clone()));
+            }
+            if outpoints.contains(&outpoint) {
+                return Err(MempoolError::Invalid(BlockValidationError::DoubleSpend(
+                    tx_id,
+                )));
+            }
+            outpoints.push(outpoint);
+        }
+
+        // 交易最早被打包进下一个区块
+        let height = blockchain.get_height().await + 1;
+        let fee = blockchain
+            .validate_transaction(&tx, height)
+            .await
+            .map_err(MempoolError::Invalid)?;
+
+        let entry = MempoolEntry { tx, fee, size };
+        for evicted in self.make_room(&entry)? {
+            println!("Evicted tx {} from the full mempool", evicted);
+            self.remove(&evicted);
+        }
+
+        for outpoint in outpoints {
+            self.spends.insert(outpoint, tx_id.clone());
+        }
+        self.total_size += size;
+        self.txs.insert(tx_id, entry);
+
+        Ok(())
+    }
+
+    /// 计算为新交易腾出空间需要剔除的交易, 按费率从低到高剔除
+    ///
+    /// 只有费率严格高于被剔除交易时才允许替换, 否则拒绝新交易
+    ///
+    /// # Arguments
+    ///
+    /// - `entry` (`&MempoolEntry`) - 待加入的交易
+    ///
+    /// # Returns
+    ///
+    /// - `Result<Vec<String>, MempoolError>` - 需要剔除的tx_id
+    fn make_room(&self, entry: &MempoolEntry) -> Result<Vec<String>, MempoolError> {
+        let mut count = self.txs.len() + 1;
+        let mut total_size = self.total_size + entry.size;
+        if count <= MAX_MEMPOOL_TXS && total_size <= MAX_MEMPOOL_BYTES {
+            return Ok(vec![]);
+        }
+
+        let mut entries: Vec<(&String, &MempoolEntry)> = self.txs.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.fee_rate());
+
+        let mut evicted = vec![];
+        for (tx_id, victim) in entries {
+            if count <= MAX_MEMPOOL_TXS && total_size <= MAX_MEMPOOL_BYTES {
+                break;
+            }
+            if victim.fee_rate() >= entry.fee_rate() {
+                return Err(MempoolError::Full);
+            }
+            evicted.push(tx_id.clone());
+            count -= 1;
+            total_size -= victim.size;
+        }
+
+        if count > MAX_MEMPOOL_TXS || total_size > MAX_MEMPOOL_BYTES {
+            return Err(MempoolError::Full);
+        }
+        Ok(evicted)
+    }
+
+    /// 主链变化后剔除输入已不可用的交易, 包括已被打包以及与新区块冲突的交易
+    ///
+    /// # Arguments
+    ///
+    /// - `utxo_set` (`&UTXOSet`) - 更新后的UTXO set
+    pub async fn evict_spent(&mut self, utxo_set: &UTXOSet) {
+        let mut evicted = vec![];
+        for (tx_id, entry) in &self.txs {
+            for input in &entry.tx.inputs {
+                if utxo_set
+                    .find_output(&input.tx_id, input.out_idx)
+                    .await
+                    .is_none()
+                {
+                    evicted.push(tx_id.clone());
+                    break;
+                }
+            }
+        }
+
+        for tx_id in evicted {
+            self.remove(&tx_id);
+        }
+    }
+
+    pub fn remove(&mut self, tx_id: &str) -> Option<Transaction> {
+        let entry = self.txs.remove(tx_id)?;
+        self.total_size -= entry.size;
+        for input in &entry.tx.inputs {
+            self.spends
+                .remove(&(hex::encode(&input.tx_id), input.out_idx));
+        }
+        Some(entry.tx)
+    }
+
+    /// 按费率从高到低取出最多limit笔、总计不超过max_bytes字节的交易用于打包
+    ///
+    /// # Arguments
+    ///
+    /// - `limit` (`usize`) - 最多打包的交易数
+    /// - `max_bytes` (`usize`) - 打包交易编码后的总字节数上限
+    ///
+    /// # Returns
+    ///
+    /// - `(Vec<Transaction>, u128)` - (待打包交易, 手续费总和)
+    pub fn select(&self, limit: usize, max_bytes: usize) -> (Vec<Transaction>, u128) {
+        let mut entries: Vec<&MempoolEntry> = self.txs.values().collect();
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.fee_rate()));
+
+        // 放不下的交易跳过, 费率更低但更小的交易仍可填充剩余空间
+        let mut remaining = max_bytes;
+        let selected: Vec<&MempoolEntry> = entries
+            .into_iter()
+            .filter(|entry| {
+                let fits = entry.size <= remaining;
+                if fits {
+                    remaining -= entry.size;
+                }
+                fits
+            })
+            .take(limit)
+            .collect();
+        let fees = selected.iter().map(|entry| entry.fee).sum();
+        let txs = selected.into_iter().map(|entry| entry.tx.clone()).collect();
+
+        (txs, fees)
+    }
+
+    pub fn contains(&self, tx_id: &str) -> bool {
+        self.txs.contains_key(tx_id)
+    }
+
+    pub fn get(&self, tx_id: &str) -> Option<&Transaction> {
+        self.txs.get(tx_id).map(|entry| &entry.tx)
+    }
+
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// 所有交易编码后的字节数之和
+    pub fn size(&self) -> usize {
+        self.total_size
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::block::Block;
+
+// 单个区块最多打包的交易池交易数
+pub const MAX_BLOCK_TXS: usize = 100;
+// 打包交易池交易时为区块头与coinbase预留的字节数
+pub const BLOCK_RESERVED_SIZE: usize = 1024;
+
+/// 节点内正在进行的挖矿任务
+pub struct MiningJob {
+    // 正在挖的区块高度
+    pub height: u128,
+    cancel: Arc<AtomicBool>,
+}
+
+impl MiningJob {
+    /// 在阻塞线程池中对区块进行工作量证明, 成功后通过sender交回节点
+    ///
+    /// # Arguments
+    ///
+    /// - `template` (`Block`) - 待挖的区块
+    /// - `sender` (`UnboundedSender<Block>`) - 挖出的区块发送通道
+    ///
+    /// # Returns
+    ///
+    /// - `Self` - 可取消的挖矿任务
+    pub fn spawn(mut template: Block, sender: UnboundedSender<Block>) -> Self {
+        let height = template.height;
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancel_flag = Arc::clone(&cancel);
+
+        tokio::task::spawn_blocking(move || {
+            loop {
+                if template.mine(&cancel_flag) {
+                    let _ = sender.send(template);
+                    return;
+                }
+                if cancel_flag.load(Ordering::Relaxed) {
+                    return;
+                }
+                // nonce已耗尽, 更新时间戳后继续, 任务在出块或取消前一直有效
+                template.refresh_timestamp();
+            }
+        });
+
+        Self { height, cancel }
+    }
+
+    /// 放弃当前任务, 例如同高度的竞争区块已经到达
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
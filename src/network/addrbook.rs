@@ -0,0 +1,209 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bincode::{Decode, Encode, config};
+
+// 地址簿最多保存的地址数
+pub const MAX_ADDRESSES: usize = 1000;
+// 连续失败达到该次数的地址被删除
+const MAX_FAILURES: u32 = 10;
+// 连接失败后的重试间隔, 秒, 按失败次数线性增加
+const RETRY_INTERVAL: u64 = 30;
+
+/// 地址簿中的一个节点地址
+#[derive(Encode, Decode, Clone)]
+pub struct AddressEntry {
+    pub addr: String,
+    // 最近一次握手成功的时间, 秒, 从未成功时为0
+    pub last_seen: u64,
+    // 最近一次发起连接的时间, 秒
+    pub last_attempt: u64,
+    // 最近一次成功之后的连续失败次数
+    pub failures: u32,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// 已知节点地址, 保存在链数据库旁边, 重启后继续使用
+pub struct AddressBook {
+    path: PathBuf,
+    entries: HashMap<String, AddressEntry>,
+    // 有未保存的修改
+    dirty: bool,
+}
+
+impl AddressBook {
+    /// 读取节点的地址簿, 文件不存在或无法解析时为空
+    ///
+    /// # Arguments
+    ///
+    /// - `node_id` (`u32`) - node_id
+    pub fn load(node_id: u32) -> Self {
+        let path = PathBuf::from(format!("./peers_{}.data", node_id));
+        let entries = fs::read(&path)
+            .ok()
+            .and_then(|data| {
+                bincode::decode_from_slice::<Vec<AddressEntry>, _>(&data, config::standard()).ok()
+            })
+            .map(|(entries, _)| {
+                entries
+                    .into_iter()
+                    .map(|entry| (entry.addr.clone(), entry))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            path,
+            entries,
+            dirty: false,
+        }
+    }
+
+    /// 有修改时写回文件
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let entries: Vec<&AddressEntry> = self.entries.values().collect();
+        let bytes = bincode::encode_to_vec(&entries, config::standard()).unwrap();
+        match fs::write(&self.path, bytes) {
+            Ok(()) => self.dirty = false,
+            Err(err) => println!("Failed to save address book: {}", err),
+        }
+    }
+
+    /// 加入新地址, 地址簿已满时淘汰失败次数最多、最久未见的地址
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 是否为新地址
+    pub fn add(&mut self, addr: &str) -> bool {
+        if self.entries.contains_key(addr) {
+            return false;
+        }
+
+        if self.entries.len() >= MAX_ADDRESSES {
+            let worst = self
+                .entries
+                .values()
+                .max_by_key(|entry| (entry.failures, u64::MAX - entry.last_seen))
+                .map(|entry| entry.addr.clone());
+            if let Some(worst) = worst {
+                self.entries.remove(&worst);
+            }
+        }
+
+        self.entries.insert(
+            addr.to_string(),
+            AddressEntry {
+                addr: addr.to_string(),
+                last_seen: 0,
+                last_attempt: 0,
+                failures: 0,
+            },
+        );
+        self.dirty = true;
+        true
+    }
+
+    pub fn remove(&mut self, addr: &str) {
+        if self.entries.remove(addr).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// 记录握手成功
+    pub fn mark_seen(&mut self, addr: &str) {
+        self.add(addr);
+        if let Some(entry) = self.entries.get_mut(addr) {
+            entry.last_seen = now_secs();
+            entry.failures = 0;
+            self.dirty = true;
+        }
+    }
+
+    pub fn mark_attempt(&mut self, addr: &str) {
+        if let Some(entry) = self.entries.get_mut(addr) {
+            entry.last_attempt = now_secs();
+            self.dirty = true;
+        }
+    }
+
+    /// 记录连接或握手失败, 失败次数过多时删除该地址
+    pub fn mark_failed(&mut self, addr: &str) {
+        let Some(entry) = self.entries.get_mut(addr) else {
+            return;
+        };
+        entry.failures += 1;
+        if entry.failures >= MAX_FAILURES {
+            self.entries.remove(addr);
+        }
+        self.dirty = true;
+    }
+
+    /// 选出可以尝试连接的地址, 最近握手成功的优先
+    ///
+    /// # Arguments
+    ///
+    /// - `count` (`usize`) - 最多返回的地址数
+    /// - `skip` (`impl Fn(&str) -> bool`) - 需要跳过的地址, 例如已连接的节点
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<String>` - 候选地址
+    pub fn candidates(&self, count: usize, skip: impl Fn(&str) -> bool) -> Vec<String> {
+        let now = now_secs();
+        let mut candidates: Vec<&AddressEntry> = self
+            .entries
+            .values()
+            .filter(|entry| !skip(&entry.addr))
+            .filter(|entry| {
+                let retry_after = RETRY_INTERVAL * entry.failures as u64;
+                now.saturating_sub(entry.last_attempt) >= retry_after
+            })
+            .collect();
+        candidates.sort_by_key(|entry| (entry.failures, u64::MAX - entry.last_seen));
+
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|entry| entry.addr.clone())
+            .collect()
+    }
+
+    /// 回复getaddr的地址, 最近握手成功的优先
+    ///
+    /// # Arguments
+    ///
+    /// - `count` (`usize`) - 最多返回的地址数
+    /// - `exclude` (`&str`) - 请求方自己的地址
+    pub fn sample(&self, count: usize, exclude: &str) -> Vec<String> {
+        let mut entries: Vec<&AddressEntry> = self
+            .entries
+            .values()
+            .filter(|entry| entry.addr != exclude && entry.failures < MAX_FAILURES / 2)
+            .collect();
+        entries.sort_by_key(|entry| u64::MAX - entry.last_seen);
+
+        entries
+            .into_iter()
+            .take(count)
+            .map(|entry| entry.addr.clone())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bincode::config;
+
+// 违规分数达到该值时断开连接
+pub const DISCONNECT_SCORE: u32 = 50;
+// 违规分数达到该值时封禁节点
+pub const BAN_SCORE: u32 = 100;
+// 封禁时长, 秒
+pub const BAN_DURATION: u64 = 24 * 60 * 60;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// 读取文件中的封禁记录, 文件不存在或无法解析时为空
+fn read_bans(path: &PathBuf) -> HashMap<String, u64> {
+    fs::read(path)
+        .ok()
+        .and_then(|data| {
+            bincode::decode_from_slice::<Vec<(String, u64)>, _>(&data, config::standard()).ok()
+        })
+        .map(|(bans, _)| bans.into_iter().collect())
+        .unwrap_or_default()
+}
+
+/// 节点的违规分数与封禁记录, 按连接的对方IP索引, 封禁记录保存到文件
+pub struct BanList {
+    path: PathBuf,
+    // IP -> 封禁到期时间, 秒
+    bans: HashMap<String, u64>,
+    // 上次读写文件时文件中的封禁记录, 用于识别其他进程对文件的修改
+    synced: HashMap<String, u64>,
+    // IP -> 累计违规分数, 断开连接后仍然保留, 只在内存中
+    scores: HashMap<String, u32>,
+}
+
+impl BanList {
+    /// 读取节点的封禁列表, 文件不存在或无法解析时为空
+    ///
+    /// # Arguments
+    ///
+    /// - `node_id` (`u32`) - node_id
+    pub fn load(node_id: u32) -> Self {
+        let path = PathBuf::from(format!("./bans_{}.data", node_id));
+        let bans = read_bans(&path);
+
+        Self {
+            path,
+            synced: bans.clone(),
+            bans,
+            scores: HashMap::default(),
+        }
+    }
+
+    /// 合并其他进程对文件的修改后写回, 运行中的节点借此得知命令行解除的封禁
+    ///
+    /// 自上次读写以来只在文件中变化的记录以文件为准, 本地修改过的记录以本地为准
+    pub fn save(&mut self) {
+        let on_disk = read_bans(&self.path);
+        for (addr, until) in &self.synced {
+            if on_disk.get(addr) != Some(until) && self.bans.get(addr) == Some(until) {
+                self.bans.remove(addr);
+                self.scores.remove(addr);
+            }
+        }
+        for (addr, until) in &on_disk {
+            if self.synced.get(addr) != Some(until) && !self.bans.contains_key(addr) {
+                self.bans.insert(addr.clone(), *until);
+            }
+        }
+
+        if self.bans == on_disk {
+            self.synced = on_disk;
+            return;
+        }
+        let bans: Vec<(&String, &u64)> = self.bans.iter().collect();
+        let bytes = bincode::encode_to_vec(&bans, config::standard()).unwrap();
+        match fs::write(&self.path, bytes) {
+            Ok(()) => self.synced = self.bans.clone(),
+            Err(err) => println!("Failed to save ban list: {}", err),
+        }
+    }
+
+    /// 累加节点的违规分数, 达到BAN_SCORE时封禁该节点并清零分数
+    ///
+    /// # Arguments
+    ///
+    /// - `addr` (`&str`) - 节点IP
+    /// - `score` (`u32`) - 本次违规的分数
+    ///
+    /// # Returns
+    ///
+    /// - `u32` - 累计后的违规分数
+    pub fn misbehaving(&mut self, addr: &str, score: u32) -> u32 {
+        let total = self.scores.entry(addr.to_string()).or_default();
+        *total = total.saturating_add(score);
+        let total = *total;
+        if total >= BAN_SCORE {
+            self.scores.remove(addr);
+            self.ban(addr, BAN_DURATION);
+        }
+        total
+    }
+
+    /// 封禁节点duration秒
+    pub fn ban(&mut self, addr: &str, duration: u64) {
+        self.bans.insert(addr.to_string(), now_secs() + duration);
+    }
+
+    pub fn is_banned(&self, addr: &str) -> bool {
+        self.bans
+            .get(addr)
+            .is_some_and(|until| *until > now_secs())
+    }
+
+    /// 解除对addr的封禁
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - addr是否在封禁列表中
+    pub fn unban(&mut self, addr: &str) -> bool {
+        self.scores.remove(addr);
+        self.bans.remove(addr).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.scores.clear();
+        self.bans.clear();
+    }
+
+    /// 删除已到期的封禁
+    pub fn expire(&mut self) {
+        let now = now_secs();
+        self.bans.retain(|_, until| *until > now);
+    }
+
+    /// 仍然有效的封禁, 按到期时间排序
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<(String, u64)>` - (节点IP, 封禁到期时间, 秒)
+    pub fn list(&self) -> Vec<(String, u64)> {
+        let now = now_secs();
+        let mut bans: Vec<(String, u64)> = self
+            .bans
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(addr, until)| (addr.clone(), *until))
+            .collect();
+        bans.sort_by_key(|(_, until)| *until);
+        bans
+    }
+}
@@ -3,18 +3,80 @@ use std::{
     sync::Arc,
 };
 
-use bincode::{Decode, Encode, config};
+use bincode::{Decode, Encode, config, error::DecodeError};
+use clap::ValueEnum;
+
+use crate::{
+    block::{Block, BlockHeader, MAX_BLOCK_SIZE},
+    emission::EmissionSchedule,
+    mempool::MAX_TX_SIZE,
+    transaction::Transaction,
+};
+
+// 本节点实现的协议版本, 即消息头中的ver
+pub const PROTOCOL_VERSION: u8 = 1;
+// 仍然兼容的最低协议版本
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+// 节点保存完整区块并能向其他节点提供
+pub const NODE_NETWORK: u64 = 1;
+pub const USER_AGENT: &str = concat!("/blockchain:", env!("CARGO_PKG_VERSION"), "/");
+// 未知命令的消息长度上限, 也是所有消息的上限
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+// 消息中区块或交易之外字段(如node_addr)的长度余量
+const PAYLOAD_OVERHEAD: usize = 1024;
+
+/// 节点所属的网络, 不同网络的节点因帧头的magic不同而无法互相通信
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Network {
+    #[clap(rename_all = "kebab-case")]
+    Mainnet,
+    #[clap(rename_all = "kebab-case")]
+    Testnet,
+}
 
-use crate::{block::Block, transaction::Transaction};
+impl Network {
+    /// 每个帧开头的4字节
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xe3, 0xc1, 0xb7, 0xd5],
+            Network::Testnet => [0x0b, 0x11, 0x09, 0x07],
+        }
+    }
+
+    /// 该网络的挖矿奖励发行计划, 测试网减半更快以便观察减半
+    pub fn emission_schedule(&self) -> EmissionSchedule {
+        match self {
+            Network::Mainnet => EmissionSchedule {
+                initial_subsidy: 100,
+                halving_interval: 210,
+                tail_emission: None,
+            },
+            Network::Testnet => EmissionSchedule {
+                initial_subsidy: 100,
+                halving_interval: 21,
+                tail_emission: Some(1),
+            },
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Cmd {
     Height,
-    Getblocks,
     SendInv,
     GetData,
     SendBlock,
     SendTx,
+    GetHeaders,
+    Headers,
+    Version,
+    Verack,
+    GetAddr,
+    Addr,
+    Ping,
+    Pong,
+    GetPeerInfo,
+    PeerInfo,
     Unknown,
 }
 
@@ -24,9 +86,6 @@ impl Display for Cmd {
             Cmd::Height => {
                 f.write_str("Height").unwrap();
             }
-            Cmd::Getblocks => {
-                f.write_str("Getblocks").unwrap();
-            }
             Cmd::SendInv => {
                 f.write_str("SendInv").unwrap();
             }
@@ -39,6 +98,36 @@ impl Display for Cmd {
             Cmd::SendTx => {
                 f.write_str("SendTx").unwrap();
             }
+            Cmd::GetHeaders => {
+                f.write_str("GetHeaders").unwrap();
+            }
+            Cmd::Headers => {
+                f.write_str("Headers").unwrap();
+            }
+            Cmd::Version => {
+                f.write_str("Version").unwrap();
+            }
+            Cmd::Verack => {
+                f.write_str("Verack").unwrap();
+            }
+            Cmd::GetAddr => {
+                f.write_str("GetAddr").unwrap();
+            }
+            Cmd::Addr => {
+                f.write_str("Addr").unwrap();
+            }
+            Cmd::Ping => {
+                f.write_str("Ping").unwrap();
+            }
+            Cmd::Pong => {
+                f.write_str("Pong").unwrap();
+            }
+            Cmd::GetPeerInfo => {
+                f.write_str("GetPeerInfo").unwrap();
+            }
+            Cmd::PeerInfo => {
+                f.write_str("PeerInfo").unwrap();
+            }
             Cmd::Unknown => {
                 f.write_str("Unknown").unwrap();
             }
@@ -52,24 +141,62 @@ impl Cmd {
     pub fn encode(&self) -> [u8; 2] {
         match self {
             Cmd::Height => [0u8, 1u8],
-            Cmd::Getblocks => [0u8, 2u8],
             Cmd::SendInv => [0u8, 3u8],
             Cmd::GetData => [0u8, 4u8],
             Cmd::SendBlock => [0u8, 5u8],
             Cmd::SendTx => [0u8, 6u8],
+            Cmd::GetHeaders => [0u8, 7u8],
+            Cmd::Headers => [0u8, 8u8],
+            Cmd::Version => [0u8, 9u8],
+            Cmd::Verack => [0u8, 10u8],
+            Cmd::GetAddr => [0u8, 11u8],
+            Cmd::Addr => [0u8, 12u8],
+            Cmd::Ping => [0u8, 13u8],
+            Cmd::Pong => [0u8, 14u8],
+            Cmd::GetPeerInfo => [0u8, 15u8],
+            Cmd::PeerInfo => [0u8, 16u8],
             Cmd::Unknown => [255u8, 255u8],
         }
     }
 
+    /// 该命令的负载长度上限, 超过时解码器直接报错而不是等待接收完整
+    pub fn max_payload_len(&self) -> usize {
+        match self {
+            Cmd::Height
+            | Cmd::GetData
+            | Cmd::Version
+            | Cmd::Verack
+            | Cmd::GetAddr
+            | Cmd::Ping
+            | Cmd::Pong
+            | Cmd::GetPeerInfo => 1024,
+            Cmd::GetHeaders | Cmd::Addr | Cmd::PeerInfo => 64 * 1024,
+            Cmd::Headers => 256 * 1024,
+            Cmd::SendTx => MAX_TX_SIZE + PAYLOAD_OVERHEAD,
+            Cmd::SendBlock => MAX_BLOCK_SIZE + PAYLOAD_OVERHEAD,
+            Cmd::SendInv => 1024 * 1024,
+            Cmd::Unknown => MAX_MESSAGE_SIZE,
+        }
+    }
+
     pub fn decode(bytes: [u8; 2]) -> Cmd {
         let seri: u16 = u16::from_be_bytes(bytes);
         match seri {
             1u16 => Cmd::Height,
-            2u16 => Cmd::Getblocks,
             3u16 => Cmd::SendInv,
             4u16 => Cmd::GetData,
             5u16 => Cmd::SendBlock,
             6u16 => Cmd::SendTx,
+            7u16 => Cmd::GetHeaders,
+            8u16 => Cmd::Headers,
+            9u16 => Cmd::Version,
+            10u16 => Cmd::Verack,
+            11u16 => Cmd::GetAddr,
+            12u16 => Cmd::Addr,
+            13u16 => Cmd::Ping,
+            14u16 => Cmd::Pong,
+            15u16 => Cmd::GetPeerInfo,
+            16u16 => Cmd::PeerInfo,
             _ => Cmd::Unknown,
         }
     }
@@ -99,6 +226,22 @@ pub trait Command {
     fn version(&self) -> u8;
 }
 
+/// 解析消息的负载, 最多读取MAX_MESSAGE_SIZE字节
+///
+/// 负载中的长度前缀由对方填写, 不限制时bincode会按声明的长度预先分配内存
+///
+/// # Arguments
+///
+/// - `package` (`&[u8]`) - 解码器输出的消息, 即ver + len + cmd + 负载
+///
+/// # Returns
+///
+/// - `Result<T, DecodeError>` - 解析出的负载
+pub fn decode_payload<T: Decode<()>>(package: &[u8]) -> Result<T, DecodeError> {
+    let config = config::standard().with_limit::<MAX_MESSAGE_SIZE>();
+    bincode::decode_from_slice(&package[7..], config).map(|(payload, _)| payload)
+}
+
 #[derive(Encode, Decode)]
 pub struct HeightCmd {
     pub node_addr: Arc<String>,
@@ -126,7 +269,7 @@ impl Command for HeightCmd {
     }
 
     fn version(&self) -> u8 {
-        1u8
+        PROTOCOL_VERSION
     }
 }
 
@@ -136,41 +279,6 @@ impl HeightCmd {
     }
 }
 
-#[derive(Decode, Encode)]
-pub struct GetblocksCmd {
-    pub node_addr: Arc<String>,
-}
-
-impl Command for GetblocksCmd {
-    fn serialize(&self) -> Vec<u8> {
-        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
-
-        let mut result = vec![];
-        let ver = self.version();
-        // ver, 1 byte
-        result.push(ver);
-        let length = payload.len() as u32;
-        // len, 4 bytes, cause HeightCmd only has a 'height' field, u32
-        result.extend_from_slice(&length.to_be_bytes());
-        // cmd, 2 bytes
-        result.extend_from_slice(&Cmd::Getblocks.encode());
-        // payload field
-        result.extend_from_slice(&payload);
-
-        result
-    }
-
-    fn version(&self) -> u8 {
-        1u8
-    }
-}
-
-impl GetblocksCmd {
-    pub fn new(node_addr: Arc<String>) -> Self {
-        Self { node_addr }
-    }
-}
-
 #[derive(Encode, Decode)]
 pub struct SendInvCmd {
     pub node_addr: Arc<String>,
@@ -199,7 +307,7 @@ impl Command for SendInvCmd {
     }
 
     fn version(&self) -> u8 {
-        1u8
+        PROTOCOL_VERSION
     }
 }
 
@@ -245,7 +353,7 @@ impl Command for GetDataCmd {
     }
 
     fn version(&self) -> u8 {
-        1u8
+        PROTOCOL_VERSION
     }
 }
 
@@ -274,7 +382,7 @@ impl Command for SendBlockCmd {
     }
 
     fn version(&self) -> u8 {
-        1u8
+        PROTOCOL_VERSION
     }
 }
 
@@ -309,7 +417,7 @@ impl Command for SendTxCmd {
     }
 
     fn version(&self) -> u8 {
-        1u8
+        PROTOCOL_VERSION
     }
 }
 
@@ -318,3 +426,395 @@ impl SendTxCmd {
         Self { node_addr, tx }
     }
 }
+
+/// 请求分叉点之后的区块头
+#[derive(Encode, Decode)]
+pub struct GetHeadersCmd {
+    pub node_addr: Arc<String>,
+    // 请求方的区块定位器, 从顶端到创世区块
+    pub locator: Vec<String>,
+    // 到该区块为止, 为空时由对方决定数量
+    pub stop_hash: String,
+}
+
+impl Command for GetHeadersCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::GetHeaders.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl GetHeadersCmd {
+    pub fn new(node_addr: Arc<String>, locator: Vec<String>, stop_hash: String) -> Self {
+        Self {
+            node_addr,
+            locator,
+            stop_hash,
+        }
+    }
+}
+
+/// 按高度从低到高排列的主链区块头
+#[derive(Encode, Decode)]
+pub struct HeadersCmd {
+    pub node_addr: Arc<String>,
+    pub headers: Vec<BlockHeader>,
+}
+
+impl Command for HeadersCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::Headers.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl HeadersCmd {
+    pub fn new(node_addr: Arc<String>, headers: Vec<BlockHeader>) -> Self {
+        Self { node_addr, headers }
+    }
+}
+
+/// 建立会话后双方发送的第一条消息
+#[derive(Encode, Decode)]
+pub struct VersionCmd {
+    pub node_addr: Arc<String>,
+    // 发送方支持的最高协议版本
+    pub version: u8,
+    // 发送方提供的服务, 按bit组合
+    pub services: u64,
+    pub height: u32,
+    pub user_agent: String,
+    // 每个节点启动时随机生成, 收到自己的nonce说明连接到了自己
+    pub nonce: u64,
+}
+
+impl Command for VersionCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::Version.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl VersionCmd {
+    pub fn new(node_addr: Arc<String>, height: u32, nonce: u64) -> Self {
+        Self {
+            node_addr,
+            version: PROTOCOL_VERSION,
+            services: NODE_NETWORK,
+            height,
+            user_agent: USER_AGENT.to_string(),
+            nonce,
+        }
+    }
+}
+
+/// 接受对方的version, 携带双方协商出的协议版本
+#[derive(Encode, Decode)]
+pub struct VerackCmd {
+    pub node_addr: Arc<String>,
+    pub version: u8,
+}
+
+impl Command for VerackCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::Verack.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl VerackCmd {
+    pub fn new(node_addr: Arc<String>, version: u8) -> Self {
+        Self { node_addr, version }
+    }
+}
+
+/// 请求对方已知的节点地址
+#[derive(Encode, Decode)]
+pub struct GetAddrCmd {
+    pub node_addr: Arc<String>,
+}
+
+impl Command for GetAddrCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::GetAddr.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl GetAddrCmd {
+    pub fn new(node_addr: Arc<String>) -> Self {
+        Self { node_addr }
+    }
+}
+
+/// 节点地址列表, 回复getaddr
+#[derive(Encode, Decode)]
+pub struct AddrCmd {
+    pub node_addr: Arc<String>,
+    pub addrs: Vec<String>,
+}
+
+impl Command for AddrCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::Addr.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl AddrCmd {
+    pub fn new(node_addr: Arc<String>, addrs: Vec<String>) -> Self {
+        Self { node_addr, addrs }
+    }
+}
+
+/// 检测对方是否仍然在线, 对方以相同的nonce回复pong
+#[derive(Encode, Decode)]
+pub struct PingCmd {
+    pub node_addr: Arc<String>,
+    pub nonce: u64,
+}
+
+impl Command for PingCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::Ping.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl PingCmd {
+    pub fn new(node_addr: Arc<String>, nonce: u64) -> Self {
+        Self { node_addr, nonce }
+    }
+}
+
+/// 对ping的回复
+#[derive(Encode, Decode)]
+pub struct PongCmd {
+    pub node_addr: Arc<String>,
+    pub nonce: u64,
+}
+
+impl Command for PongCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::Pong.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl PongCmd {
+    pub fn new(node_addr: Arc<String>, nonce: u64) -> Self {
+        Self { node_addr, nonce }
+    }
+}
+
+/// 请求节点当前的连接信息, 由命令行使用
+#[derive(Encode, Decode)]
+pub struct GetPeerInfoCmd {
+    pub node_addr: Arc<String>,
+}
+
+impl Command for GetPeerInfoCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::GetPeerInfo.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl GetPeerInfoCmd {
+    pub fn new(node_addr: Arc<String>) -> Self {
+        Self { node_addr }
+    }
+}
+
+/// 一个连接的概要信息
+#[derive(Encode, Decode)]
+pub struct PeerSummary {
+    pub addr: String,
+    pub user_agent: String,
+    pub version: u8,
+    pub start_height: u32,
+    // 是否由本节点主动连接
+    pub outbound: bool,
+    // 最近一次测得的往返延迟, 毫秒
+    pub latency_ms: Option<u64>,
+}
+
+/// 对getpeerinfo的回复
+#[derive(Encode, Decode)]
+pub struct PeerInfoCmd {
+    pub node_addr: Arc<String>,
+    pub peers: Vec<PeerSummary>,
+}
+
+impl Command for PeerInfoCmd {
+    fn serialize(&self) -> Vec<u8> {
+        let payload = bincode::encode_to_vec(self, config::standard()).unwrap();
+
+        let mut result = vec![];
+
+        let ver = self.version();
+        result.push(ver);
+
+        let length = payload.len() as u32;
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&Cmd::PeerInfo.encode());
+
+        result.extend_from_slice(&payload);
+
+        result
+    }
+
+    fn version(&self) -> u8 {
+        PROTOCOL_VERSION
+    }
+}
+
+impl PeerInfoCmd {
+    pub fn new(node_addr: Arc<String>, peers: Vec<PeerSummary>) -> Self {
+        Self { node_addr, peers }
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::block::Block;
+
+// 每个节点同时在途的区块请求上限
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 16;
+// 在途与已下载未接入的区块总数上限, 防止落后的区块卡住时无限向前下载
+pub const MAX_BLOCKS_AHEAD: usize = 256;
+// 区块请求超时后改向其他节点重新请求
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+// 尚未测得延迟的节点按该延迟估计
+const DEFAULT_LATENCY: Duration = Duration::from_millis(500);
+// 暂存的区块超过该时间父区块仍未接入时, 释放占用的位置并稍后重新下载
+const HELD_BLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 一个在途的区块请求
+struct InFlight {
+    peer: String,
+    requested_at: Instant,
+}
+
+/// 已下载但父区块尚未接入的区块
+struct Held {
+    peer: String,
+    block: Block,
+    received_at: Instant,
+}
+
+/// 区块下载调度器: 按区块头顺序向多个节点并发请求区块体, 父区块到达后按顺序接入
+#[derive(Default)]
+pub struct BlockDownloader {
+    // 等待请求的区块hash, 按高度从低到高
+    pending: VecDeque<String>,
+    // 区块hash -> 可以提供该区块的节点
+    sources: HashMap<String, HashSet<String>>,
+    in_flight: HashMap<String, InFlight>,
+    // 父区块hash -> 已下载但父区块尚未接入的区块, 每个父区块只暂存一个
+    received: HashMap<String, Held>,
+    // 节点 -> 最近测得的往返延迟
+    latencies: HashMap<String, Duration>,
+}
+
+impl BlockDownloader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 登记peer可以提供的区块, 已在下载中的区块只增加来源
+    ///
+    /// # Arguments
+    ///
+    /// - `peer` (`&str`) - 节点地址
+    /// - `hashes` (`impl IntoIterator<Item = String>`) - 区块hash, 按高度从低到高
+    pub fn add(&mut self, peer: &str, hashes: impl IntoIterator<Item = String>) {
+        for hash in hashes {
+            let sources = self.sources.entry(hash.clone()).or_default();
+            let is_new = sources.is_empty();
+            sources.insert(peer.to_string());
+            if is_new && !self.is_held(&hash) {
+                self.pending.push_back(hash);
+            }
+        }
+    }
+
+    fn in_flight_count(&self, peer: &str) -> usize {
+        self.in_flight
+            .values()
+            .filter(|request| request.peer == peer)
+            .count()
+    }
+
+    /// 记录节点最近测得的往返延迟
+    pub fn set_latency(&mut self, peer: &str, latency: Duration) {
+        self.latencies.insert(peer.to_string(), latency);
+    }
+
+    /// 按节点的在途请求数与延迟估计新请求完成所需的时间
+    fn estimated_cost(&self, peer: &str, in_flight: usize) -> Duration {
+        let latency = self
+            .latencies
+            .get(peer)
+            .copied()
+            .unwrap_or(DEFAULT_LATENCY);
+        latency * (in_flight as u32 + 1)
+    }
+
+    /// 为等待中的区块分配节点, 每个区块选预计最快完成且未满的来源
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<(String, String)>` - 需要发送的(节点地址, 区块hash)
+    pub fn schedule(&mut self) -> Vec<(String, String)> {
+        let mut requests = vec![];
+        let mut deferred = VecDeque::new();
+
+        while self.in_flight.len() + self.received.len() < MAX_BLOCKS_AHEAD {
+            let Some(hash) = self.pending.pop_front() else {
+                break;
+            };
+            let Some(sources) = self.sources.get(&hash) else {
+                continue;
+            };
+
+            let peer = sources
+                .iter()
+                .map(|peer| (self.in_flight_count(peer), peer))
+                .filter(|(count, _)| *count < MAX_IN_FLIGHT_PER_PEER)
+                .map(|(count, peer)| (self.estimated_cost(peer, count), peer))
+                .min()
+                .map(|(_, peer)| peer.clone());
+            match peer {
+                Some(peer) => {
+                    self.in_flight.insert(
+                        hash.clone(),
+                        InFlight {
+                            peer: peer.clone(),
+                            requested_at: Instant::now(),
+                        },
+                    );
+                    requests.push((peer, hash));
+                }
+                // 来源节点都已满, 保留顺序等待下一轮
+                None => deferred.push_back(hash),
+            }
+        }
+
+        deferred.extend(self.pending.drain(..));
+        self.pending = deferred;
+        requests
+    }
+
+    /// 把超时的请求放回等待队列, 有其他来源时不再向超时的节点请求该区块
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| now.duration_since(request.requested_at) >= BLOCK_DOWNLOAD_TIMEOUT)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+
+        for hash in expired {
+            let request = self.in_flight.remove(&hash).unwrap();
+            println!("Block {} from {} timed out", &hash, &request.peer);
+            if let Some(sources) = self.sources.get_mut(&hash)
+                && sources.len() > 1
+            {
+                sources.remove(&request.peer);
+            }
+            self.pending.push_front(hash);
+        }
+
+        // 父区块迟迟未接入的暂存区块放回等待队列, 之后重新向发送节点请求
+        let stale: Vec<String> = self
+            .received
+            .iter()
+            .filter(|(_, held)| now.duration_since(held.received_at) >= HELD_BLOCK_TIMEOUT)
+            .map(|(parent_hash, _)| parent_hash.clone())
+            .collect();
+        for parent_hash in stale {
+            let held = self.received.remove(&parent_hash).unwrap();
+            println!("Dropped block {} waiting for parent {}", &held.block.hash, &parent_hash);
+            self.add(&held.peer, [held.block.hash]);
+        }
+    }
+
+    /// 记录收到的区块, 不再请求该区块
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 该区块是否正在向peer请求
+    pub fn block_received(&mut self, peer: &str, hash: &str) -> bool {
+        let requested = self
+            .in_flight
+            .remove(hash)
+            .is_some_and(|request| request.peer == peer);
+        self.pending.retain(|pending| pending != hash);
+        self.sources.remove(hash);
+        requested
+    }
+
+    fn is_held(&self, hash: &str) -> bool {
+        self.received.values().any(|held| held.block.hash == hash)
+    }
+
+    /// 区块是否正在等待下载、下载中或已下载待接入
+    pub fn is_tracking(&self, hash: &str) -> bool {
+        self.sources.contains_key(hash) || self.is_held(hash)
+    }
+
+    /// 暂存向peer请求到的、父区块尚未接入的区块, 同一父区块已有暂存的子区块时不再暂存
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 是否已暂存
+    pub fn hold(&mut self, peer: &str, block: Block) -> bool {
+        if self.received.contains_key(&block.header.prev_hash) {
+            return false;
+        }
+        let held = Held {
+            peer: peer.to_string(),
+            block,
+            received_at: Instant::now(),
+        };
+        self.received.insert(held.block.header.prev_hash.clone(), held);
+        true
+    }
+
+    /// 取出以parent_hash为父区块的已下载区块
+    ///
+    /// # Returns
+    ///
+    /// - `Option<(String, Block)>` - (发送节点, 区块)
+    pub fn take_child(&mut self, parent_hash: &str) -> Option<(String, Block)> {
+        self.received
+            .remove(parent_hash)
+            .map(|held| (held.peer, held.block))
+    }
+
+    /// 区块被拒绝后丢弃该节点提供的后续区块以及依赖该区块的已下载区块
+    ///
+    /// # Arguments
+    ///
+    /// - `peer` (`&str`) - 提供无效区块的节点
+    /// - `hash` (`&str`) - 被拒绝的区块hash
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<String>` - 不再下载的区块hash
+    pub fn reject(&mut self, peer: &str, hash: &str) -> Vec<String> {
+        let mut dropped = self.remove_peer(peer);
+
+        let mut invalid = vec![hash.to_string()];
+        while let Some(parent_hash) = invalid.pop() {
+            while let Some((_, child)) = self.take_child(&parent_hash) {
+                invalid.push(child.hash);
+            }
+            if !dropped.contains(&parent_hash) {
+                dropped.push(parent_hash);
+            }
+        }
+        dropped
+    }
+
+    /// 不再向peer请求区块, 只能由该节点提供的区块不再下载
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<String>` - 不再下载的区块hash
+    pub fn remove_peer(&mut self, peer: &str) -> Vec<String> {
+        self.latencies.remove(peer);
+        let mut dropped = vec![];
+        self.sources.retain(|hash, sources| {
+            sources.remove(peer);
+            if sources.is_empty() {
+                dropped.push(hash.clone());
+            }
+            !sources.is_empty()
+        });
+
+        // 向该节点的在途请求改由其他来源提供
+        let cancelled: Vec<String> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.peer == peer)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in cancelled {
+            self.in_flight.remove(&hash);
+            self.pending.push_front(hash);
+        }
+
+        let sources = &self.sources;
+        self.pending.retain(|pending| sources.contains_key(pending));
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::block;
+
+    fn hashes(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("b{}", i)).collect()
+    }
+
+    #[test]
+    fn schedule_requests_each_block_once_in_order() {
+        let mut downloader = BlockDownloader::new();
+        downloader.add("a", hashes(3));
+        downloader.add("b", hashes(3));
+
+        let requests = downloader.schedule();
+        let requested: Vec<&str> = requests.iter().map(|(_, hash)| hash.as_str()).collect();
+        assert_eq!(requested, ["b0", "b1", "b2"]);
+        assert!(downloader.schedule().is_empty());
+    }
+
+    #[test]
+    fn schedule_limits_requests_per_peer() {
+        let mut downloader = BlockDownloader::new();
+        downloader.add("a", hashes(MAX_IN_FLIGHT_PER_PEER + 4));
+
+        assert_eq!(downloader.schedule().len(), MAX_IN_FLIGHT_PER_PEER);
+        downloader.block_received("a", "b0");
+        let requests = downloader.schedule();
+        assert_eq!(requests, [(String::from("a"), format!("b{}", MAX_IN_FLIGHT_PER_PEER))]);
+    }
+
+    #[test]
+    fn schedule_prefers_faster_peer() {
+        let mut downloader = BlockDownloader::new();
+        downloader.set_latency("slow", Duration::from_millis(400));
+        downloader.set_latency("fast", Duration::from_millis(100));
+        downloader.add("slow", hashes(4));
+        downloader.add("fast", hashes(4));
+
+        // fast的第4个请求预计耗时与slow的第1个相同
+        let requests = downloader.schedule();
+        let fast = requests.iter().filter(|(peer, _)| peer == "fast").count();
+        assert!(fast >= 3, "{:?}", requests);
+    }
+
+    #[test]
+    fn remove_peer_moves_requests_to_other_sources() {
+        let mut downloader = BlockDownloader::new();
+        downloader.add("a", hashes(2));
+        downloader.add("b", vec![String::from("b0")]);
+        downloader.set_latency("b", Duration::from_secs(10));
+        assert!(downloader.schedule().iter().all(|(peer, _)| peer == "a"));
+
+        // 只有a能提供的b1不再下载
+        assert_eq!(downloader.remove_peer("a"), ["b1"]);
+        assert_eq!(downloader.schedule(), [(String::from("b"), String::from("b0"))]);
+        assert!(!downloader.is_tracking("b1"));
+    }
+
+    #[test]
+    fn held_blocks_are_taken_by_parent() {
+        let mut downloader = BlockDownloader::new();
+        downloader.add("a", hashes(2));
+        downloader.schedule();
+        assert!(downloader.block_received("a", "b1"));
+        assert!(downloader.hold("a", block("b1", "b0")));
+        assert!(downloader.is_tracking("b1"));
+
+        assert!(downloader.take_child("other").is_none());
+        let (peer, child) = downloader.take_child("b0").unwrap();
+        assert_eq!((peer.as_str(), child.hash.as_str()), ("a", "b1"));
+        assert!(!downloader.is_tracking("b1"));
+    }
+
+    #[test]
+    fn reject_drops_descendants_and_peer() {
+        let mut downloader = BlockDownloader::new();
+        downloader.add("a", hashes(4));
+        downloader.schedule();
+        for (hash, prev_hash) in [("b1", "b0"), ("b2", "b1")] {
+            downloader.block_received("a", hash);
+            downloader.hold("a", block(hash, prev_hash));
+        }
+
+        let mut dropped = downloader.reject("a", "b0");
+        dropped.sort();
+        assert_eq!(dropped, ["b0", "b1", "b2", "b3"]);
+        assert!(!downloader.is_tracking("b1"));
+        assert!(!downloader.is_tracking("b2"));
+        assert!(!downloader.is_tracking("b3"));
+        assert!(downloader.schedule().is_empty());
+    }
+
+    #[test]
+    fn only_requested_blocks_are_held_once_per_parent() {
+        let mut downloader = BlockDownloader::new();
+        downloader.add("a", hashes(2));
+        downloader.schedule();
+
+        // 未向该节点请求过的区块
+        assert!(!downloader.block_received("b", "b1"));
+        assert!(!downloader.block_received("a", "x1"));
+
+        downloader.add("a", hashes(2));
+        downloader.schedule();
+        assert!(downloader.block_received("a", "b1"));
+        assert!(downloader.hold("a", block("b1", "b0")));
+        assert!(!downloader.hold("a", block("x1", "b0")));
+        assert_eq!(downloader.take_child("b0").unwrap().1.hash, "b1");
+    }
+
+    #[test]
+    fn expire_requeues_stale_held_blocks() {
+        let mut downloader = BlockDownloader::new();
+        downloader.add("a", hashes(2));
+        downloader.schedule();
+        downloader.block_received("a", "b1");
+        downloader.hold("a", block("b1", "b0"));
+
+        downloader.received.get_mut("b0").unwrap().received_at -= HELD_BLOCK_TIMEOUT;
+        downloader.expire();
+        assert!(downloader.take_child("b0").is_none());
+        assert_eq!(downloader.schedule(), [(String::from("a"), String::from("b1"))]);
+    }
+}
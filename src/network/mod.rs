@@ -1,46 +1,146 @@
+pub mod addrbook;
+pub mod banlist;
 pub mod command;
+pub mod download;
+pub mod peer;
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, hash_map::RandomState},
+    fmt::Display,
+    hash::{BuildHasher, Hasher},
+    rc::Rc,
+    slice,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
-use bincode::config;
+use bincode::Decode;
 use bytes::{BufMut, BytesMut};
-use futures::{SinkExt, StreamExt};
+use sha2::{Digest, Sha256};
 use tokio::{
     io,
-    net::{TcpListener, TcpStream},
+    net::TcpListener,
+    sync::mpsc::{self, Receiver, Sender, UnboundedSender},
 };
-use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{
-    blockchain::Blockchain,
-    network::command::{
-        Cmd, Command, GetDataCmd, GetblocksCmd, HeightCmd, InvType, SendBlockCmd, SendInvCmd,
+    block::{Block, MAX_BLOCK_SIZE},
+    blockchain::{BlockValidationError, Blockchain},
+    mempool::{Mempool, MempoolError},
+    miner::{BLOCK_RESERVED_SIZE, MAX_BLOCK_TXS, MiningJob},
+    network::{
+        addrbook::AddressBook,
+        banlist::{BAN_DURATION, BAN_SCORE, BanList, DISCONNECT_SCORE},
+        command::{
+            AddrCmd, Cmd, Command, GetAddrCmd, GetDataCmd, GetHeadersCmd, GetPeerInfoCmd,
+            HeadersCmd, HeightCmd, InvType, MIN_PROTOCOL_VERSION, NODE_NETWORK, Network,
+            PROTOCOL_VERSION, PeerInfoCmd, PeerSummary, PingCmd, PongCmd, SendBlockCmd,
+            SendInvCmd, SendTxCmd, VerackCmd, VersionCmd,
+        },
+        download::BlockDownloader,
+        peer::{KnownInventory, PeerEvent, PeerInfo, Peers, SessionHandle, SessionId},
     },
+    orphan::OrphanPool,
+    transaction::Transaction,
+    utxo::UTXOSet,
 };
 
+// 单个headers消息最多携带的区块头数量
+const MAX_HEADERS: usize = 500;
+// 检查区块请求超时的间隔
+const DOWNLOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// 主动连接的节点数目标
+const TARGET_OUTBOUND_PEERS: usize = 8;
+// 检查连接数并补充连接的间隔
+const CONNECT_INTERVAL: Duration = Duration::from_secs(10);
+// 最多接受的连接数
+const MAX_INBOUND_PEERS: usize = 32;
+// 超过该时间仍未完成握手的会话将被断开
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// 检查握手超时的间隔
+const HANDSHAKE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// 向每个节点发送ping的间隔
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// 超过该时间未回复pong的节点视为失去响应
+const PING_TIMEOUT: Duration = Duration::from_secs(60);
+// 批量通告新交易的间隔
+const TX_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+// 单个inv消息最多携带的条目数
+const MAX_INV_ITEMS: usize = 1000;
+// 单个addr消息最多携带的地址数
+const MAX_ADDRS: usize = 100;
+// 等待主循环处理的会话事件上限, 已满时各会话暂停读取
+const MAX_PENDING_PEER_EVENTS: usize = 64;
+// 各类违规行为的分数
+const MALFORMED_MESSAGE_SCORE: u32 = 50;
+const INVALID_DATA_SCORE: u32 = 100;
+const OVERSIZED_MESSAGE_SCORE: u32 = 20;
+const UNCONNECTED_HEADERS_SCORE: u32 = 10;
+const UNKNOWN_COMMAND_SCORE: u32 = 10;
+
 pub struct Server {
     pub node_id: u32,
+    pub network: Network,
     pub node_address: Arc<String>,
-    pub miner_address: String,
-    pub known_hosts: Vec<String>,
-    // todo!Mutex
-    pub blocks_in_transimission: HashMap<String, VecDeque<String>>,
+    // 为None时不挖矿
+    pub miner_address: Option<String>,
+    // 启动时连接的种子节点
+    pub seeds: Vec<String>,
+    pub address_book: AddressBook,
+    pub ban_list: BanList,
+    // 主动连接的会话, 包括尚在连接中的
+    outbound: HashSet<SessionId>,
+    pub downloader: BlockDownloader,
+    pub orphans: OrphanPool,
+    pub mempool: Mempool,
+    pub mining_job: Option<MiningJob>,
+    pub peers: Peers,
+    // 已收到version的节点地址 -> PeerInfo
+    pub peer_info: HashMap<String, PeerInfo>,
+    // 已发送过version的会话
+    versions_sent: HashSet<SessionId>,
+    // 回环地址的会话 -> 累计违规分数
+    loopback_scores: HashMap<SessionId, u32>,
+    // 用于识别连接到自己的情况
+    nonce: u64,
+    // 所有会话共用的事件通道, 接收端在start_node中取出
+    peer_events: Sender<PeerEvent>,
+    peer_event_receiver: Option<Receiver<PeerEvent>>,
 }
 
 pub struct Handler {
 }
 
 impl Server {
-    pub fn new(node_id: u32, miner_address: String) -> Self {
+    pub fn new(
+        node_id: u32,
+        network: Network,
+        listen_address: String,
+        seeds: Vec<String>,
+        miner_address: Option<String>,
+    ) -> Self {
+        let (peer_events, peer_event_receiver) = mpsc::channel(MAX_PENDING_PEER_EVENTS);
         Self {
             node_id,
-            node_address: Arc::new(format!("localhost:{}", node_id)),
+            network,
+            node_address: Arc::new(listen_address),
             miner_address,
-            known_hosts: vec![String::from("localhost:3000")],
-            blocks_in_transimission: HashMap::default(),
+            seeds,
+            address_book: AddressBook::load(node_id),
+            ban_list: BanList::load(node_id),
+            outbound: HashSet::default(),
+            downloader: BlockDownloader::new(),
+            orphans: OrphanPool::new(),
+            mempool: Mempool::new(),
+            mining_job: None,
+            peers: Peers::new(network),
+            peer_info: HashMap::default(),
+            versions_sent: HashSet::default(),
+            loopback_scores: HashMap::default(),
+            nonce: RandomState::new().build_hasher().finish(),
+            peer_events,
+            peer_event_receiver: Some(peer_event_receiver),
         }
     }
 
@@ -52,29 +152,344 @@ impl Server {
     /// - `miner_address` (`String`) - miner address
     pub async fn start_node(&mut self) {
         // continue local blockchain
-        let blockchain = Blockchain::continue_chain(self.node_id).await;
+        let emission = self.network.emission_schedule();
+        let blockchain = Rc::new(Blockchain::continue_chain(self.node_id, emission).await);
+        let utxo_set = UTXOSet::new(Rc::clone(&blockchain));
         // start node server
-        let addr = format!("localhost:{}", self.node_id);
-        let listener = TcpListener::bind(&addr).await.unwrap();
+        let listener = TcpListener::bind(self.node_address.as_str()).await.unwrap();
 
-        // sync version to center node
-        if &addr != self.known_hosts.get(0).unwrap() {
-            println!("Send [version] to center node");
-            let center_addr = Arc::new(self.known_hosts.get(0).unwrap().clone());
-            self.send_height(center_addr, &blockchain).await.unwrap();
+        // 种子节点与地址簿中的地址一起参与连接
+        for seed in &self.seeds {
+            if seed != self.node_address.as_str() {
+                self.address_book.add(seed);
+            }
         }
-        println!("Start listening...");
+        println!(
+            "Start listening on {} ({:?}), {} known address(es)",
+            &self.node_address,
+            self.network,
+            self.address_book.len()
+        );
+        let mut peer_events = self
+            .peer_event_receiver
+            .take()
+            .expect("Node is already started");
+        let (mined_sender, mut mined_receiver) = mpsc::unbounded_channel();
+        let mut download_timer = tokio::time::interval(DOWNLOAD_CHECK_INTERVAL);
+        let mut connect_timer = tokio::time::interval(CONNECT_INTERVAL);
+        let mut ping_timer = tokio::time::interval(PING_INTERVAL);
+        let mut handshake_timer = tokio::time::interval(HANDSHAKE_CHECK_INTERVAL);
+        let mut announce_timer = tokio::time::interval(TX_ANNOUNCE_INTERVAL);
         // process income
         loop {
-            let (socket, _) = listener.accept().await.unwrap();
-            let mut framed = Framed::new(socket, LengthHeaderDelimiter {});
-            while let Some(Ok(package)) = framed.next().await {
-                self.process(package, &blockchain).await;
+            self.try_mine(&blockchain, &mined_sender).await;
+
+            tokio::select! {
+                accepted = listener.accept() => {
+                    match accepted {
+                        // 每个连接由独立的会话任务收发, 消息回到主循环处理
+                        Ok((_, peer_addr)) if self.ban_list.is_banned(&peer_addr.ip().to_string()) => {
+                            println!("Refuse connection from banned {}", peer_addr.ip());
+                        }
+                        Ok((socket, _)) if self.peers.inbound_count() >= MAX_INBOUND_PEERS => {
+                            println!("Too many inbound connections, drop {:?}", socket.peer_addr());
+                        }
+                        Ok((socket, _)) => self.peers.accept(socket, self.peer_events.clone()),
+                        Err(err) => println!("Failed to accept connection: {}", err),
+                    }
+                }
+                Some(event) = peer_events.recv() => match event {
+                    PeerEvent::Package { session, package } => {
+                        self.process(package, &session, &blockchain, &utxo_set).await;
+                    }
+                    PeerEvent::Invalid { session, error } => {
+                        self.handle_invalid_frame(&session, error);
+                    }
+                    PeerEvent::Closed(id) => self.handle_closed(id, &blockchain).await,
+                },
+                Some(block) = mined_receiver.recv() => {
+                    self.handle_mined_block(block, &blockchain, &utxo_set).await;
+                }
+                _ = download_timer.tick() => {
+                    self.downloader.expire();
+                    self.orphans.expire();
+                    self.request_blocks().await;
+                }
+                _ = connect_timer.tick() => {
+                    self.maintain_connections(&blockchain).await;
+                }
+                _ = ping_timer.tick() => {
+                    self.ping_peers().await;
+                }
+                _ = handshake_timer.tick() => {
+                    self.expire_handshakes();
+                }
+                _ = announce_timer.tick() => {
+                    self.announce_txs().await;
+                }
+            }
+        }
+    }
+
+    /// 主动连接的节点不足时从地址簿补充连接, 并保存地址簿
+    async fn maintain_connections(&mut self, blockchain: &Blockchain) {
+        let missing = TARGET_OUTBOUND_PEERS.saturating_sub(self.outbound.len());
+        let candidates = self.address_book.candidates(missing, |addr| {
+            addr == self.node_address.as_str() || self.peers.get(addr).is_some()
+        });
+        for addr in candidates {
+            println!("Connect to {}", addr);
+            self.connect_peer(&addr, blockchain).await;
+        }
+
+        self.address_book.save();
+        self.ban_list.expire();
+        self.ban_list.save();
+    }
+
+    /// 断开超过HANDSHAKE_TIMEOUT仍未交换version与verack的会话
+    fn expire_handshakes(&mut self) {
+        for session in self.peers.opened_before(HANDSHAKE_TIMEOUT) {
+            let completed = self
+                .peers
+                .addr_of(session.id)
+                .and_then(|addr| self.peer_info.get(&addr))
+                .is_some_and(|info| info.verack);
+            if !completed {
+                self.disconnect(&session, "handshake timeout");
+            }
+        }
+    }
+
+    /// 断开ping超时的节点, 并向没有未回复ping的节点发送新的ping
+    async fn ping_peers(&mut self) {
+        let mut unresponsive = vec![];
+        let mut idle = vec![];
+        for (addr, info) in &self.peer_info {
+            match info.pending_ping {
+                Some((_, sent_at)) if sent_at.elapsed() >= PING_TIMEOUT => {
+                    unresponsive.push(addr.clone());
+                }
+                Some(_) => {}
+                None => idle.push(addr.clone()),
+            }
+        }
+
+        for addr in unresponsive {
+            if let Some(session) = self.peers.get(&addr) {
+                self.disconnect(&session, "ping timeout");
+            }
+        }
+        for addr in idle {
+            self.send_ping(&addr).await;
+        }
+    }
+
+    async fn send_ping(&mut self, addr: &str) {
+        let nonce = RandomState::new().build_hasher().finish();
+        let ping_cmd = PingCmd::new(Arc::clone(&self.node_address), nonce);
+        let result = self.transmit(addr, ping_cmd).await;
+        if result.is_ok()
+            && let Some(info) = self.peer_info.get_mut(addr)
+        {
+            info.pending_ping = Some((nonce, Instant::now()));
+        }
+        log_send_error(result, addr);
+    }
+
+    /// 若开启了挖矿且没有进行中的任务, 用交易池中的交易组装区块并开始挖矿
+    async fn try_mine(&mut self, blockchain: &Blockchain, sender: &UnboundedSender<Block>) {
+        let Some(miner_address) = &self.miner_address else {
+            return;
+        };
+        if self.mining_job.is_some() || self.mempool.is_empty() {
+            return;
+        }
+
+        let (selected, fees) = self
+            .mempool
+            .select(MAX_BLOCK_TXS, MAX_BLOCK_SIZE - BLOCK_RESERVED_SIZE);
+        let height = blockchain.get_height().await + 1;
+        let reward = blockchain.emission.subsidy(height) + fees;
+        let mut transactions = vec![Transaction::coinbase_tx(miner_address.clone(), height, reward)];
+        transactions.extend(selected);
+
+        if let Some(template) = blockchain.block_template(transactions).await {
+            println!(
+                "Start mining block {} with {} tx(s)",
+                template.height,
+                template.transactions.len()
+            );
+            self.mining_job = Some(MiningJob::spawn(template, sender.clone()));
+        }
+    }
+
+    async fn handle_mined_block(
+        &mut self,
+        block: Block,
+        blockchain: &Blockchain,
+        utxo_set: &UTXOSet,
+    ) {
+        self.mining_job = None;
+
+        let block_hash = block.hash.clone();
+        let tx_ids: Vec<String> = block.transactions.iter().map(|tx| hex::encode(&tx.id)).collect();
+        if let Err(err) = blockchain.add_block(block).await {
+            println!("Discarded mined block {}: {}", block_hash, err);
+            return;
+        }
+        println!("Mined block {}", block_hash);
+        self.evict_mined(&tx_ids, utxo_set).await;
+
+        // 向尚不知道新区块的节点广播
+        for (host, info) in &self.peer_info {
+            if info.known_inventory.contains(&block_hash) {
+                continue;
             }
+            let inv_cmd = SendInvCmd::new(
+                Arc::clone(&self.node_address),
+                InvType::Block,
+                vec![block_hash.clone()],
+            );
+            if let Err(err) = self.transmit(host, inv_cmd).await {
+                println!("Failed to broadcast block {} to {}: {}", block_hash, host, err);
+            }
+        }
+        for info in self.peer_info.values_mut() {
+            info.known_inventory.insert(&block_hash);
         }
     }
 
-    async fn process(&mut self, package: Vec<u8>, blockchain: &Blockchain) {
+    /// 剔除已被打包或与新主链冲突的交易
+    async fn evict_mined(&mut self, tx_ids: &[String], utxo_set: &UTXOSet) {
+        for tx_id in tx_ids {
+            self.mempool.remove(tx_id);
+        }
+        self.mempool.evict_spent(utxo_set).await;
+    }
+
+    /// 主链高度已达到正在挖的高度时, 放弃当前挖矿任务
+    async fn cancel_stale_mining(&mut self, blockchain: &Blockchain) {
+        let Some(job) = &self.mining_job else {
+            return;
+        };
+        if blockchain.get_height().await >= job.height {
+            println!("Competing block arrived, cancel mining block {}", job.height);
+            job.cancel();
+            self.mining_job = None;
+        }
+    }
+
+    /// 会话关闭后把该节点的在途区块请求交给其他节点, 删除不再下载的区块头
+    async fn handle_closed(&mut self, id: SessionId, blockchain: &Blockchain) {
+        self.versions_sent.remove(&id);
+        self.loopback_scores.remove(&id);
+        let outbound = self.outbound.remove(&id);
+        if let Some(addr) = self.peers.remove(id) {
+            println!("Peer {} disconnected", addr);
+            // 主动连接未能完成握手, 推迟重试该地址
+            if outbound && !self.peer_info.contains_key(&addr) {
+                self.address_book.mark_failed(&addr);
+            }
+            self.peer_info.remove(&addr);
+            let dropped = self.downloader.remove_peer(&addr);
+            blockchain.forget_headers(&dropped).await;
+        }
+    }
+
+    /// 其他网络的节点只断开连接, 其余无法解析的帧记为违规
+    fn handle_invalid_frame(&mut self, session: &SessionHandle, error: FrameError) {
+        let reason = format!("invalid frame: {}", error);
+        if matches!(error, FrameError::WrongMagic(_)) {
+            self.disconnect(session, &reason);
+        } else {
+            self.misbehaving(session, MALFORMED_MESSAGE_SCORE, &reason);
+        }
+    }
+
+    /// 连接addr并发起握手, addr解析出的IP被封禁时不连接
+    async fn connect_peer(&mut self, addr: &str, blockchain: &Blockchain) {
+        self.address_book.mark_attempt(addr);
+        if let Ok(mut resolved) = tokio::net::lookup_host(addr).await
+            && resolved.any(|peer_addr| self.ban_list.is_banned(&peer_addr.ip().to_string()))
+        {
+            return println!("Skip banned peer {}", addr);
+        }
+        let session = self.peers.connect(addr, self.peer_events.clone());
+        self.outbound.insert(session.id);
+        self.send_version(&session, blockchain).await;
+    }
+
+    fn disconnect(&self, session: &SessionHandle, reason: &str) {
+        match self.peers.addr_of(session.id) {
+            Some(addr) => println!("Disconnect {}: {}", addr, reason),
+            None => println!("Disconnect session {}: {}", session.id, reason),
+        }
+        session.close();
+    }
+
+    /// 解析消息负载, 无法解析时记为对方违规
+    fn decode_payload<T: Decode<()>>(
+        &mut self,
+        package: &[u8],
+        session: &SessionHandle,
+    ) -> Option<T> {
+        match command::decode_payload(package) {
+            Ok(payload) => Some(payload),
+            Err(err) => {
+                let reason = format!("malformed payload: {}", err);
+                self.misbehaving(session, MALFORMED_MESSAGE_SCORE, &reason);
+                None
+            }
+        }
+    }
+
+    /// 按连接的对方IP累加违规分数, 达到DISCONNECT_SCORE时断开连接, 达到BAN_SCORE时封禁该IP
+    ///
+    /// 同一台机器上的其他节点与命令行都来自回环地址, 封禁回环地址会把它们一并拒之门外,
+    /// 因此回环地址只按会话累计分数并断开连接, 不封禁
+    ///
+    /// # Arguments
+    ///
+    /// - `session` (`&SessionHandle`) - 违规的会话
+    /// - `score` (`u32`) - 本次违规的分数
+    /// - `reason` (`&str`) - 违规原因
+    fn misbehaving(&mut self, session: &SessionHandle, score: u32, reason: &str) {
+        let Some(ip) = self.peers.ip_of(session.id) else {
+            return self.disconnect(session, reason);
+        };
+        let loopback = ip.is_loopback();
+        let ip = ip.to_string();
+        let total = if loopback {
+            let total = self.loopback_scores.entry(session.id).or_default();
+            *total = total.saturating_add(score);
+            *total
+        } else {
+            self.ban_list.misbehaving(&ip, score)
+        };
+        match self.peers.addr_of(session.id) {
+            Some(addr) => println!(
+                "Peer {} ({}) misbehaving (+{} -> {}): {}",
+                addr, ip, score, total, reason
+            ),
+            None => println!("Peer {} misbehaving (+{} -> {}): {}", ip, score, total, reason),
+        }
+        if total >= BAN_SCORE && !loopback {
+            println!("Ban {} for {}s", ip, BAN_DURATION);
+            self.ban_list.save();
+        }
+        if total >= DISCONNECT_SCORE {
+            self.disconnect(session, reason);
+        }
+    }
+
+    async fn process(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+        utxo_set: &UTXOSet,
+    ) {
         let ver = package[0];
         let cmd = Cmd::decode(package[5..7].try_into().unwrap());
         println!(
@@ -85,97 +500,385 @@ impl Server {
         );
 
         match cmd {
-            Cmd::Height => self.handle_height(package, blockchain).await,
-            Cmd::Getblocks => self.handle_getblocks(package, blockchain).await,
-            Cmd::SendInv => self.handle_invcmd(package).await,
-            Cmd::GetData => self.handle_getdatacmd(package, blockchain).await,
-            Cmd::SendBlock => self.handle_sendblockcmd(package, blockchain).await,
-            Cmd::SendTx => {}
-            Cmd::Unknown => {
-                println!("Receive unknown cmd!!");
+            Cmd::Version => return self.handle_version(package, session, blockchain).await,
+            Cmd::Verack => return self.handle_verack(package, session).await,
+            _ => {}
+        }
+
+        // 握手完成前只接受version, 之后的消息必须使用协商出的协议版本
+        let negotiated = self
+            .peers
+            .addr_of(session.id)
+            .and_then(|addr| self.peer_info.get(&addr))
+            .map(|info| info.version);
+        match negotiated {
+            None => return self.disconnect(session, "message before handshake"),
+            Some(version) if version != ver => {
+                return self.disconnect(session, "unexpected protocol version");
+            }
+            Some(_) => {}
+        }
+
+        match cmd {
+            Cmd::Height => self.handle_height(package, session, blockchain).await,
+            Cmd::SendInv => self.handle_invcmd(package, session, blockchain).await,
+            Cmd::GetData => self.handle_getdatacmd(package, session, blockchain).await,
+            Cmd::SendBlock => {
+                self.handle_sendblockcmd(package, session, blockchain, utxo_set)
+                    .await
             }
+            Cmd::SendTx => self.handle_sendtxcmd(package, session, blockchain).await,
+            Cmd::GetHeaders => self.handle_getheaders(package, session, blockchain).await,
+            Cmd::Headers => self.handle_headers(package, session, blockchain).await,
+            Cmd::GetAddr => self.handle_getaddr(package, session).await,
+            Cmd::Addr => self.handle_addr(package, session),
+            Cmd::Ping => self.handle_ping(package, session).await,
+            Cmd::Pong => self.handle_pong(package, session),
+            Cmd::GetPeerInfo => self.handle_getpeerinfo(package, session).await,
+            Cmd::PeerInfo => {}
+            Cmd::Version | Cmd::Verack => {}
+            Cmd::Unknown => self.misbehaving(session, UNKNOWN_COMMAND_SCORE, "unknown command"),
         }
     }
 
-    async fn handle_getblocks(&self, package: Vec<u8>, blockchain: &Blockchain) {
-        let (payload, _): (GetblocksCmd, usize) =
-            bincode::decode_from_slice(&package[7..], config::standard()).unwrap();
-        let addr_from = payload.node_addr;
-        let block_hashes = blockchain.get_block_hashes().await;
+    async fn handle_getheaders(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+    ) {
+        let Some(payload) = self.decode_payload::<GetHeadersCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
 
-        let node_addr = Arc::clone(&self.node_address);
-        let send_inv_cmd = SendInvCmd::new(node_addr, InvType::Block, block_hashes);
+        let headers = blockchain
+            .headers_after(&payload.locator, &payload.stop_hash, MAX_HEADERS)
+            .await;
+        let headers_cmd = HeadersCmd::new(Arc::clone(&self.node_address), headers);
 
-        self.transmit(&addr_from, send_inv_cmd).await.unwrap();
+        let result = self.transmit(&addr, headers_cmd).await;
+        log_send_error(result, &addr);
     }
 
-    async fn handle_invcmd(&mut self, package: Vec<u8>) {
-        let (payload, _): (SendInvCmd, usize) =
-            bincode::decode_from_slice(&package[7..], config::standard()).unwrap();
+    /// 逐个校验收到的区块头, 再向对方请求通过校验的区块体
+    async fn handle_headers(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+    ) {
+        let Some(payload) = self.decode_payload::<HeadersCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
+
+        if payload.headers.len() > MAX_HEADERS {
+            let reason = "too many headers";
+            return self.misbehaving(session, OVERSIZED_MESSAGE_SCORE, reason);
+        }
+
+        let full_batch = payload.headers.len() >= MAX_HEADERS;
+        let (accepted, rejected) = blockchain.accept_headers(payload.headers).await;
+        let mut missing = vec![];
+        for entry in &accepted {
+            if !blockchain.has_block(&entry.hash).await && !self.orphans.contains(&entry.hash) {
+                missing.push(entry.hash.clone());
+            }
+        }
 
-        // 取出当前请求的block id
-        let mut block_ids: VecDeque<String> = payload.items.into_iter().collect();
-        let block_id = block_ids.pop_front().unwrap_or_default();
+        // 后续区块头都建立在被拒绝的区块头之上, 不再继续同步该节点的链
+        let mut last_hash = accepted.last().map(|entry| entry.hash.clone());
+        if let Some(err) = rejected {
+            let score = match &err {
+                BlockValidationError::UnknownParent => UNCONNECTED_HEADERS_SCORE,
+                err => block_error_score(err),
+            };
+            if score > 0 {
+                let reason = format!("invalid headers: {}", err);
+                self.misbehaving(session, score, &reason);
+            } else {
+                println!("Stop syncing headers from {}: {}", &addr, err);
+            }
+            last_hash = None;
+        }
 
-        // 剩下的直接存到server.blocks_in_transimission
-        // todo!: host_key下原有的带请求blocks
-        self.blocks_in_transimission
-            .insert(payload.node_addr.to_string(), block_ids);
+        // 对方还有更多区块头, 从收到的最后一个继续请求
+        if full_batch && let Some(last_hash) = last_hash {
+            let mut locator = vec![last_hash];
+            locator.extend(blockchain.block_locator().await);
+            let result = self.send_getheaders(&addr, locator).await;
+            log_send_error(result, &addr);
+        }
+
+        // 按高度从低到高下载区块体, 由调度器分配给各个节点
+        self.downloader.add(&addr, missing);
+        self.request_blocks().await;
+    }
+
+    /// 发送调度器分配的区块请求, 发送失败的请求等待超时后重新分配
+    async fn request_blocks(&mut self) {
+        for (peer, hash) in self.downloader.schedule() {
+            if let Err(err) = self.send_getdata(&peer, InvType::Block, &hash).await {
+                println!("Failed to request block {} from {}: {}", hash, peer, err);
+            }
+        }
+    }
+
+    async fn handle_invcmd(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+    ) {
+        let Some(payload) = self.decode_payload::<SendInvCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
+        if payload.items.len() > MAX_INV_ITEMS {
+            let reason = "too many inventory items";
+            return self.misbehaving(session, OVERSIZED_MESSAGE_SCORE, reason);
+        }
+        self.mark_known(&addr, &payload.items);
 
         let inv_type = payload.inv_type;
         match inv_type {
             InvType::Block => {
-                self.send_getdata(&payload.node_addr, InvType::Block, &block_id)
-                    .await
-                    .unwrap();
+                // 对方按从顶端到创世块的顺序发送, 先请求祖先区块以便逐个通过校验
+                let mut block_ids = vec![];
+                for block_id in payload.items.into_iter().rev() {
+                    if !blockchain.has_block(&block_id).await && !self.orphans.contains(&block_id) {
+                        block_ids.push(block_id);
+                    }
+                }
+
+                self.downloader.add(&addr, block_ids);
+                self.request_blocks().await;
             }
             InvType::Tx => {
-                self.send_getdata(&payload.node_addr, InvType::Tx, &block_id)
-                    .await
-                    .unwrap();
+                // 只请求交易池中没有的交易
+                for tx_id in payload.items {
+                    if self.mempool.contains(&tx_id) {
+                        continue;
+                    }
+                    let result = self.send_getdata(&addr, InvType::Tx, &tx_id).await;
+                    log_send_error(result, &addr);
+                }
+            }
+        }
+    }
+
+    async fn handle_sendtxcmd(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+    ) {
+        let Some(payload) = self.decode_payload::<SendTxCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
+
+        let tx_id = hex::encode(&payload.tx.id);
+        self.mark_known(&addr, slice::from_ref(&tx_id));
+        if let Err(err) = self.mempool.accept(payload.tx, blockchain).await {
+            println!("Rejected tx {} from {}: {}", tx_id, &addr, err);
+            let score = tx_error_score(&err);
+            if score > 0 {
+                self.misbehaving(session, score, &format!("invalid tx: {}", err));
+            }
+            return;
+        }
+        println!(
+            "Accepted tx {}, mempool: {} tx(s), {} bytes",
+            tx_id,
+            self.mempool.len(),
+            self.mempool.size()
+        );
+
+        // 等待下一次批量通告时告知其他节点
+        for info in self.peer_info.values_mut() {
+            if !info.known_inventory.contains(&tx_id) {
+                info.tx_announcements.push(tx_id.clone());
             }
         }
     }
 
-    async fn handle_sendblockcmd(&mut self, package: Vec<u8>, blockchain: &Blockchain) {
-        let (payload, _): (SendBlockCmd, usize) =
-            bincode::decode_from_slice(&package[7..], config::standard()).unwrap();
+    /// 把各节点待通告的交易合并成inv发送, 已被打包或对方已知的交易不再通告
+    async fn announce_txs(&mut self) {
+        let mut announcements = vec![];
+        for (host, info) in self.peer_info.iter_mut() {
+            let mut tx_ids = vec![];
+            for tx_id in info.tx_announcements.drain(..) {
+                if self.mempool.contains(&tx_id) && info.known_inventory.insert(&tx_id) {
+                    tx_ids.push(tx_id);
+                }
+            }
+            if !tx_ids.is_empty() {
+                announcements.push((host.clone(), tx_ids));
+            }
+        }
+
+        for (host, tx_ids) in announcements {
+            for items in tx_ids.chunks(MAX_INV_ITEMS) {
+                let inv_cmd =
+                    SendInvCmd::new(Arc::clone(&self.node_address), InvType::Tx, items.to_vec());
+                if let Err(err) = self.transmit(&host, inv_cmd).await {
+                    println!("Failed to announce {} tx(s) to {}: {}", items.len(), host, err);
+                }
+            }
+        }
+    }
+
+    /// 记录节点已有的区块或交易, 之后不再向其通告
+    fn mark_known(&mut self, addr: &str, ids: &[String]) {
+        if let Some(info) = self.peer_info.get_mut(addr) {
+            for id in ids {
+                info.known_inventory.insert(id);
+            }
+        }
+    }
+
+    async fn handle_sendblockcmd(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+        utxo_set: &UTXOSet,
+    ) {
+        let Some(payload) = self.decode_payload::<SendBlockCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
 
-        // 添加block入库
         let block = payload.block;
-        blockchain.add_block(block).await;
+        self.mark_known(&addr, slice::from_ref(&block.hash));
+        let requested = self.downloader.block_received(&addr, &block.hash);
+
+        let prev_hash = block.header.prev_hash.clone();
+        if prev_hash.is_empty() || blockchain.has_block(&prev_hash).await {
+            self.connect_downloaded(&addr, block, blockchain, utxo_set).await;
+            self.request_blocks().await;
+            return;
+        }
+
+        // 暂存前先完成不依赖父区块的校验, 避免无效区块占用内存
+        if let Err(err) = Blockchain::check_block(&block) {
+            println!("Rejected block {} from {}: {}", &block.hash, &addr, err);
+            let dropped = self.downloader.reject(&addr, &block.hash);
+            blockchain.forget_headers(&dropped).await;
+            let score = block_error_score(&err);
+            if score > 0 {
+                self.misbehaving(session, score, &format!("invalid block: {}", err));
+            }
+        } else if requested && self.downloader.is_tracking(&prev_hash) {
+            // 并发下载时子区块可能先于父区块到达, 暂存到父区块接入后再处理
+            if !self.downloader.hold(&addr, block) {
+                println!("Ignored block from {}: another child of {} is held", &addr, &prev_hash);
+            }
+        } else {
+            // 没有在下载父区块, 放入孤块池并向发送方补齐缺失的祖先区块
+            let block_hash = block.hash.clone();
+            if self.orphans.add(&addr, block) {
+                println!(
+                    "Orphan block {} from {}, orphan pool size: {}",
+                    block_hash,
+                    &addr,
+                    self.orphans.len()
+                );
+                let locator = blockchain.block_locator().await;
+                let result = self.send_getheaders(&addr, locator).await;
+                log_send_error(result, &addr);
+            }
+        }
 
-        // 获取下一个待获取的block
-        if !self.blocks_in_transimission.is_empty() {
-            let host_key = self.blocks_in_transimission.keys().next().unwrap().clone();
-            let blocks = self.blocks_in_transimission.get_mut(&host_key).unwrap();
+        self.request_blocks().await;
+    }
 
-            if let Some(block_to_get) = blocks.pop_front() {
-                self.send_getdata(&host_key, InvType::Block, &block_to_get)
-                    .await
-                    .unwrap();
+    /// 校验并添加区块, 随后依次接入已下载的子区块与孤块
+    async fn connect_downloaded(
+        &mut self,
+        peer: &str,
+        block: Block,
+        blockchain: &Blockchain,
+        utxo_set: &UTXOSet,
+    ) {
+        let mut next = vec![(peer.to_string(), block)];
+        while let Some((peer, block)) = next.pop() {
+            let block_hash = block.hash.clone();
+            let tx_ids: Vec<String> =
+                block.transactions.iter().map(|tx| hex::encode(&tx.id)).collect();
+            if let Err(err) = blockchain.add_block(block).await {
+                println!("Rejected block {} from {}: {}", block_hash, peer, err);
+                // 后续区块依赖被拒绝的区块, 不再继续向该节点请求
+                let dropped = self.downloader.reject(&peer, &block_hash);
+                blockchain.forget_headers(&dropped).await;
+                self.orphans.discard_descendants(&block_hash);
+                // 提供区块的节点已断开时无法再追究
+                let score = block_error_score(&err);
+                if score > 0
+                    && let Some(session) = self.peers.get(&peer)
+                {
+                    self.misbehaving(&session, score, &format!("invalid block: {}", err));
+                }
+                continue;
             }
+
+            self.evict_mined(&tx_ids, utxo_set).await;
+            self.cancel_stale_mining(blockchain).await;
+
+            next.extend(self.downloader.take_child(&block_hash));
+            next.extend(self.orphans.take_children(&block_hash));
         }
     }
 
-    async fn handle_getdatacmd(&self, package: Vec<u8>, blockchain: &Blockchain) {
-        let (payload, _): (GetDataCmd, usize) =
-            bincode::decode_from_slice(&package[7..], config::standard()).unwrap();
+    async fn handle_getdatacmd(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+    ) {
+        let Some(payload) = self.decode_payload::<GetDataCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
 
         let id = payload.id;
-        let addr_from = payload.node_addr;
+        // 对方请求的数据即将由本节点提供, 之后不再向其通告
+        self.mark_known(&addr, slice::from_ref(&id));
         match payload.inv_type {
             InvType::Block => {
-                let id_bytes = hex::decode(&id).unwrap();
+                let Ok(id_bytes) = hex::decode(&id) else {
+                    return self.misbehaving(session, MALFORMED_MESSAGE_SCORE, "malformed block id");
+                };
                 if let Some(block) = blockchain.get_block(&id_bytes).await {
                     let send_block_cmd = SendBlockCmd::new(Arc::clone(&self.node_address), block);
-                    self.transmit(&addr_from, send_block_cmd).await.unwrap();
+                    let result = self.transmit(&addr, send_block_cmd).await;
+                    log_send_error(result, &addr);
                 } else {
                     println!("Cannot find target block, id: {}", &id);
                 }
             }
             InvType::Tx => {
-                todo!();
+                if let Some(tx) = self.mempool.get(&id) {
+                    let send_tx_cmd = SendTxCmd::new(Arc::clone(&self.node_address), tx.clone());
+                    let result = self.transmit(&addr, send_tx_cmd).await;
+                    log_send_error(result, &addr);
+                } else {
+                    println!("Cannot find target tx, id: {}", &id);
+                }
             }
         }
     }
@@ -194,40 +897,276 @@ impl Server {
         Ok(())
     }
 
-    async fn handle_height(&self, package: Vec<u8>, blockchain: &Blockchain) {
-        let (payload, _): (HeightCmd, usize) =
-            bincode::decode_from_slice(&package[7..], config::standard()).unwrap();
+    /// 校验对方的version, 兼容时登记会话并回复verack, 随后按双方高度开始同步
+    async fn handle_version(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+    ) {
+        let Some(payload) = self.decode_payload::<VersionCmd>(&package, session) else {
+            return;
+        };
+
+        if payload.nonce == self.nonce {
+            // 地址指向自己, 不再尝试连接
+            if let Some(addr) = self.peers.addr_of(session.id) {
+                self.address_book.remove(&addr);
+            }
+            return self.disconnect(session, "connected to self");
+        }
+        if self
+            .peers
+            .ip_of(session.id)
+            .is_some_and(|ip| self.ban_list.is_banned(&ip.to_string()))
+        {
+            return self.disconnect(session, "banned");
+        }
+        // 主动连接的节点用于同步区块, 必须提供完整区块
+        let outbound = self.versions_sent.contains(&session.id);
+        let full_node = payload.services & NODE_NETWORK != 0;
+        if payload.version < MIN_PROTOCOL_VERSION || (outbound && !full_node) {
+            return self.disconnect(
+                session,
+                &format!(
+                    "incompatible peer, version {}, services {}",
+                    payload.version, payload.services
+                ),
+            );
+        }
+        // 主动连接的节点声明的地址必须与拨号地址一致
+        if let Some(dialled) = self.peers.addr_of(session.id)
+            && dialled != payload.node_addr.as_str()
+        {
+            return self.disconnect(
+                session,
+                &format!("address mismatch, declared {}", payload.node_addr),
+            );
+        }
+        if !self.peers.register(&payload.node_addr, session) {
+            return self.disconnect(session, "already connected");
+        }
+        if self.peer_info.contains_key(payload.node_addr.as_str()) {
+            return;
+        }
+
+        let version = payload.version.min(PROTOCOL_VERSION);
+        println!(
+            "Handshake with {} {}, version {}, height {}",
+            &payload.node_addr, &payload.user_agent, version, payload.height
+        );
+        self.peer_info.insert(
+            payload.node_addr.to_string(),
+            PeerInfo {
+                version,
+                services: payload.services,
+                user_agent: payload.user_agent,
+                start_height: payload.height,
+                verack: false,
+                outbound,
+                pending_ping: None,
+                latency: None,
+                known_inventory: KnownInventory::default(),
+                tx_announcements: vec![],
+            },
+        );
+
+        if !outbound {
+            self.send_version(session, blockchain).await;
+        }
+        let verack_cmd = VerackCmd::new(Arc::clone(&self.node_address), version);
+        let result = self.transmit(&payload.node_addr, verack_cmd).await;
+        log_send_error(result, &payload.node_addr);
+
+        if !full_node {
+            return;
+        }
+
+        // 记录能够提供区块的节点, 并向主动连接的节点获取更多地址
+        self.address_book.mark_seen(&payload.node_addr);
+        if outbound {
+            let get_addr_cmd = GetAddrCmd::new(Arc::clone(&self.node_address));
+            let result = self.transmit(&payload.node_addr, get_addr_cmd).await;
+            log_send_error(result, &payload.node_addr);
+        }
+        // 对方的链可能更长, 先同步区块头
+        if blockchain.get_height().await <= payload.height as u128 {
+            let locator = blockchain.block_locator().await;
+            let result = self.send_getheaders(&payload.node_addr, locator).await;
+            log_send_error(result, &payload.node_addr);
+        }
+    }
+
+    async fn handle_verack(&mut self, package: Vec<u8>, session: &SessionHandle) {
+        let Some(payload) = self.decode_payload::<VerackCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return self.disconnect(session, "verack before version");
+        };
+
+        let Some(info) = self.peer_info.get_mut(addr.as_str()) else {
+            return self.disconnect(session, "verack before version");
+        };
+        if payload.version != info.version {
+            return self.disconnect(session, "protocol version mismatch");
+        }
+        info.verack = true;
+
+        // 握手完成后立即测量延迟, 供区块下载选择节点
+        self.send_ping(&addr).await;
+    }
+
+    async fn handle_ping(&mut self, package: Vec<u8>, session: &SessionHandle) {
+        let Some(payload) = self.decode_payload::<PingCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
+
+        let pong_cmd = PongCmd::new(Arc::clone(&self.node_address), payload.nonce);
+        let result = self.transmit(&addr, pong_cmd).await;
+        log_send_error(result, &addr);
+    }
+
+    /// 以与未回复ping的nonce匹配的pong计算往返延迟
+    fn handle_pong(&mut self, package: Vec<u8>, session: &SessionHandle) {
+        let Some(payload) = self.decode_payload::<PongCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
+
+        let Some(info) = self.peer_info.get_mut(addr.as_str()) else {
+            return;
+        };
+        let Some((nonce, sent_at)) = info.pending_ping else {
+            return;
+        };
+        if nonce != payload.nonce {
+            return;
+        }
+
+        let latency = sent_at.elapsed();
+        info.pending_ping = None;
+        info.latency = Some(latency);
+        self.downloader.set_latency(&addr, latency);
+    }
+
+    async fn handle_getpeerinfo(&mut self, package: Vec<u8>, session: &SessionHandle) {
+        if self.decode_payload::<GetPeerInfoCmd>(&package, session).is_none() {
+            return;
+        }
+        let Some(requester) = self.peers.addr_of(session.id) else {
+            return;
+        };
+
+        let peers = peer_summaries(&self.peer_info, &requester);
+        let peer_info_cmd = PeerInfoCmd::new(Arc::clone(&self.node_address), peers);
+        let result = self.transmit(&requester, peer_info_cmd).await;
+        log_send_error(result, &requester);
+    }
+
+    async fn send_version(&mut self, session: &SessionHandle, blockchain: &Blockchain) {
+        let height = blockchain.get_height().await;
+        let version_cmd = VersionCmd::new(Arc::clone(&self.node_address), height as u32, self.nonce);
+        if session.send(version_cmd.serialize()).is_ok() {
+            self.versions_sent.insert(session.id);
+        }
+    }
+
+    async fn handle_getaddr(&mut self, package: Vec<u8>, session: &SessionHandle) {
+        if self.decode_payload::<GetAddrCmd>(&package, session).is_none() {
+            return;
+        }
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
+
+        let addrs = self.address_book.sample(MAX_ADDRS, &addr);
+        let addr_cmd = AddrCmd::new(Arc::clone(&self.node_address), addrs);
+        let result = self.transmit(&addr, addr_cmd).await;
+        log_send_error(result, &addr);
+    }
+
+    /// 把收到的地址加入地址簿, 由maintain_connections决定是否连接
+    fn handle_addr(&mut self, package: Vec<u8>, session: &SessionHandle) {
+        let Some(payload) = self.decode_payload::<AddrCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
+
+        if payload.addrs.len() > MAX_ADDRS {
+            self.misbehaving(session, OVERSIZED_MESSAGE_SCORE, "too many addresses");
+        }
+
+        let mut added = 0;
+        for addr in payload.addrs.iter().take(MAX_ADDRS) {
+            if addr != self.node_address.as_str() && self.address_book.add(addr) {
+                added += 1;
+            }
+        }
+        println!(
+            "Learned {} new address(es) from {}, {} known",
+            added,
+            &addr,
+            self.address_book.len()
+        );
+    }
+
+    async fn handle_height(
+        &mut self,
+        package: Vec<u8>,
+        session: &SessionHandle,
+        blockchain: &Blockchain,
+    ) {
+        let Some(payload) = self.decode_payload::<HeightCmd>(&package, session) else {
+            return;
+        };
+        let Some(addr) = self.peers.addr_of(session.id) else {
+            return;
+        };
+
         let local_height = blockchain.get_height().await;
         if local_height > payload.height as u128 {
             // send version
-            self.send_height(payload.node_addr, blockchain)
-                .await
-                .unwrap();
+            let result = self.send_height(&addr, blockchain).await;
+            log_send_error(result, &addr);
         } else {
-            // send get blocks
-            self.send_getblocks(payload.node_addr).await.unwrap();
+            // 对方的链可能更长, 先同步区块头
+            let locator = blockchain.block_locator().await;
+            let result = self.send_getheaders(&addr, locator).await;
+            log_send_error(result, &addr);
         }
     }
 
     async fn send_height(
         &self,
-        addr: Arc<String>,
+        addr: &str,
         blockchain: &Blockchain,
     ) -> Result<(), io::Error> {
         let height = blockchain.get_height().await;
         let height_cmd = HeightCmd::new(Arc::clone(&self.node_address), height as u32);
-        self.transmit(&addr, height_cmd).await?;
+        self.transmit(addr, height_cmd).await?;
 
         println!("Sent height cmd");
 
         Ok(())
     }
 
-    async fn send_getblocks(&self, addr: Arc<String>) -> Result<(), io::Error> {
-        let cmd = GetblocksCmd::new(self.node_address.clone());
-        self.transmit(&addr, cmd).await?;
+    async fn send_getheaders(
+        &self,
+        addr: &str,
+        locator: Vec<String>,
+    ) -> Result<(), io::Error> {
+        let cmd = GetHeadersCmd::new(self.node_address.clone(), locator, String::default());
+        self.transmit(addr, cmd).await?;
 
-        println!("Sent getblocks cmd");
+        println!("Sent getheaders cmd");
 
         Ok(())
     }
@@ -236,39 +1175,175 @@ impl Server {
 impl Handler {
 }
 
-pub struct LengthHeaderDelimiter;
+/// 区块校验错误对应的违规分数, 父区块未知或时钟偏差等对方无过错的情况不计分
+fn block_error_score(err: &BlockValidationError) -> u32 {
+    match err {
+        BlockValidationError::UnknownParent
+        | BlockValidationError::TimestampTooNew
+        | BlockValidationError::InsufficientChainWork
+        | BlockValidationError::TooManyPendingHeaders => 0,
+        _ => INVALID_DATA_SCORE,
+    }
+}
+
+/// 交易被拒绝时对应的违规分数, 重复、冲突或输入未确认可能由传播延迟导致, 不计分
+fn tx_error_score(err: &MempoolError) -> u32 {
+    match err {
+        MempoolError::AlreadyKnown | MempoolError::Conflict(_) | MempoolError::Full => 0,
+        MempoolError::Invalid(
+            BlockValidationError::MissingInput(_) | BlockValidationError::ImmatureCoinbase(_),
+        ) => 0,
+        MempoolError::Invalid(err) => block_error_score(err),
+        MempoolError::TooLarge(_) => OVERSIZED_MESSAGE_SCORE,
+        MempoolError::Coinbase => INVALID_DATA_SCORE,
+    }
+}
+
+/// 按地址排序的连接概要, 不包含请求方自身
+///
+/// # Arguments
+///
+/// - `peer_info` (`&HashMap<String, PeerInfo>`) - 已握手的连接
+/// - `requester` (`&str`) - 发起getpeerinfo的一方的地址
+///
+/// # Returns
+///
+/// - `Vec<PeerSummary>` - 其余连接的概要
+fn peer_summaries(peer_info: &HashMap<String, PeerInfo>, requester: &str) -> Vec<PeerSummary> {
+    let mut peers: Vec<PeerSummary> = peer_info
+        .iter()
+        .filter(|(addr, _)| addr.as_str() != requester)
+        .map(|(addr, info)| PeerSummary {
+            addr: addr.clone(),
+            user_agent: info.user_agent.clone(),
+            version: info.version,
+            start_height: info.start_height,
+            outbound: info.outbound,
+            latency_ms: info.latency.map(|latency| latency.as_millis() as u64),
+        })
+        .collect();
+    peers.sort_by(|a, b| a.addr.cmp(&b.addr));
+    peers
+}
+
+/// 会话可能随时被对方关闭, 发送失败只记录日志
+fn log_send_error(result: Result<(), io::Error>, addr: &str) {
+    if let Err(err) = result {
+        println!("Failed to send to {}: {}", addr, err);
+    }
+}
+
+// 帧头长度: magic 4字节, ver 1字节, len 4字节, cmd 2字节, checksum 4字节
+const FRAME_HEADER_LEN: usize = 15;
+
+/// 无法解析的帧, 出现后会话即关闭
+#[derive(Debug)]
+pub enum FrameError {
+    // 对方属于其他网络
+    WrongMagic([u8; 4]),
+    Oversized { cmd: Cmd, len: usize },
+    BadChecksum,
+    Io(io::Error),
+}
+
+impl Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::WrongMagic(magic) => {
+                write!(f, "wrong network magic {}", hex::encode(magic))
+            }
+            FrameError::Oversized { cmd, len } => write!(
+                f,
+                "{} message of {} bytes exceeds the limit of {}",
+                cmd,
+                len,
+                cmd.max_payload_len()
+            ),
+            FrameError::BadChecksum => f.write_str("payload checksum mismatch"),
+            FrameError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+/// 负载sha256的前4字节
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    Sha256::digest(payload)[..4].try_into().unwrap()
+}
+
+/// 消息帧编解码器
+///
+/// 线上的帧为magic + ver + len + cmd + checksum + payload, 解码后去掉magic与checksum,
+/// 得到与Command::serialize相同的ver + len + cmd + payload
+pub struct LengthHeaderDelimiter {
+    magic: [u8; 4],
+}
+
+impl LengthHeaderDelimiter {
+    pub fn new(network: Network) -> Self {
+        Self {
+            magic: network.magic(),
+        }
+    }
+}
 
 impl Decoder for LengthHeaderDelimiter {
     type Item = Vec<u8>;
-    type Error = io::Error;
+    type Error = FrameError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // fixed length is 7 bytes
-        if src.len() < 7 {
+        if src.len() < FRAME_HEADER_LEN {
             return Ok(None);
         }
-        let _ver = u8::from_be_bytes(src[..1].try_into().unwrap());
-        let content_len = u32::from_be_bytes(src[1..5].try_into().unwrap());
-        let payload_len = content_len + 7;
+        if src[..4] != self.magic {
+            return Err(FrameError::WrongMagic(src[..4].try_into().unwrap()));
+        }
+        let content_len = u32::from_be_bytes(src[5..9].try_into().unwrap()) as usize;
+        let cmd = Cmd::decode(src[9..11].try_into().unwrap());
+        // 在接收负载之前检查长度, 不为超长的消息缓冲数据
+        if content_len > cmd.max_payload_len() {
+            return Err(FrameError::Oversized {
+                cmd,
+                len: content_len,
+            });
+        }
 
-        if src.len() < payload_len as usize {
+        let frame_len = FRAME_HEADER_LEN + content_len;
+        if src.len() < frame_len {
+            src.reserve(frame_len - src.len());
             return Ok(None);
         }
 
-        let payload: Vec<u8> = src.split_to(payload_len as usize).into();
+        let mut frame = src.split_to(frame_len);
+        if frame[11..15] != checksum(&frame[FRAME_HEADER_LEN..]) {
+            return Err(FrameError::BadChecksum);
+        }
 
-        Ok(Some(payload))
+        let mut package = Vec::with_capacity(7 + content_len);
+        package.extend_from_slice(&frame[4..11]);
+        package.extend_from_slice(&frame.split_off(FRAME_HEADER_LEN));
+
+        Ok(Some(package))
     }
 }
 
 impl Encoder<BytesMut> for LengthHeaderDelimiter {
-    type Error = io::Error;
+    type Error = FrameError;
 
+    /// item为Command::serialize的结果, 加上magic与checksum后写出
     fn encode(&mut self, item: BytesMut, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let len = item.len();
+        let payload = &item[7..];
 
-        dst.reserve(len);
-        dst.put(item);
+        dst.reserve(FRAME_HEADER_LEN + payload.len());
+        dst.put_slice(&self.magic);
+        dst.put_slice(&item[..7]);
+        dst.put_slice(&checksum(payload));
+        dst.put_slice(payload);
 
         Ok(())
     }
@@ -280,15 +1355,129 @@ trait Transmitter {
 
 impl Transmitter for Server {
     async fn transmit<T: Command>(&self, addr: &str, cmd: T) -> Result<(), io::Error> {
-        // dial to target addr
-        let client_stream = TcpStream::connect(addr).await.unwrap();
-        let mut framed = Framed::new(client_stream, LengthHeaderDelimiter {});
+        // 只通过已建立的会话发送, 新连接需先经过握手
+        let session = self.peers.get(addr).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, format!("not connected to {}", addr))
+        })?;
 
-        let mut payload = BytesMut::new();
-        payload.extend_from_slice(&cmd.serialize());
-        // framed.send.await返回的是Result<(), framed的编解码器的错误类型>
-        framed.send(payload).await?;
+        // 握手后使用协商出的协议版本
+        let mut bytes = cmd.serialize();
+        if let Some(info) = self.peer_info.get(addr) {
+            bytes[0] = info.version;
+        }
+        session.send(bytes)
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 把ping消息编码成主网的帧
+    fn ping_frame() -> BytesMut {
+        let ping_cmd = PingCmd::new(Arc::new(String::from("localhost:3000")), 42);
+        let mut frame = BytesMut::new();
+        LengthHeaderDelimiter::new(Network::Mainnet)
+            .encode(BytesMut::from(&ping_cmd.serialize()[..]), &mut frame)
+            .unwrap();
+        frame
+    }
+
+    #[test]
+    fn decode_returns_serialized_command() {
+        let ping_cmd = PingCmd::new(Arc::new(String::from("localhost:3000")), 42);
+        let mut src = ping_frame();
+        let package = LengthHeaderDelimiter::new(Network::Mainnet)
+            .decode(&mut src)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(package, ping_cmd.serialize());
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_waits_for_whole_frame() {
+        let frame = ping_frame();
+        let mut codec = LengthHeaderDelimiter::new(Network::Mainnet);
+        let mut src = BytesMut::from(&frame[..frame.len() - 1]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        src.extend_from_slice(&frame[frame.len() - 1..]);
+        assert!(codec.decode(&mut src).unwrap().is_some());
+    }
+
+    #[test]
+    fn decode_rejects_other_network() {
+        let mut src = ping_frame();
+        let result = LengthHeaderDelimiter::new(Network::Testnet).decode(&mut src);
+        assert!(matches!(result, Err(FrameError::WrongMagic(magic)) if magic == Network::Mainnet.magic()));
+    }
+
+    #[test]
+    fn decode_rejects_oversized_payload_before_buffering() {
+        let mut src = ping_frame();
+        let len = Cmd::Ping.max_payload_len() as u32 + 1;
+        src[5..9].copy_from_slice(&len.to_be_bytes());
+
+        let result = LengthHeaderDelimiter::new(Network::Mainnet).decode(&mut src);
+        assert!(matches!(
+            result,
+            Err(FrameError::Oversized { cmd: Cmd::Ping, len: actual }) if actual == len as usize
+        ));
+    }
+
+    #[test]
+    fn decode_rejects_bad_checksum() {
+        let mut src = ping_frame();
+        let last = src.len() - 1;
+        src[last] ^= 0xff;
+
+        let result = LengthHeaderDelimiter::new(Network::Mainnet).decode(&mut src);
+        assert!(matches!(result, Err(FrameError::BadChecksum)));
+    }
+
+    #[test]
+    fn decode_payload_rejects_oversized_length_prefix() {
+        // node_addr的长度前缀声称有2^40字节, 整个负载只有18字节
+        let mut payload = vec![0xfd];
+        payload.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        payload.extend_from_slice(&[42; 9]);
+        let mut package = vec![PROTOCOL_VERSION];
+        package.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        package.extend_from_slice(&Cmd::Ping.encode());
+        package.extend_from_slice(&payload);
+
+        let mut codec = LengthHeaderDelimiter::new(Network::Mainnet);
+        let mut src = BytesMut::new();
+        codec.encode(BytesMut::from(&package[..]), &mut src).unwrap();
+        let package = codec.decode(&mut src).unwrap().unwrap();
+
+        assert!(command::decode_payload::<PingCmd>(&package).is_err());
+    }
+
+    #[test]
+    fn peer_summaries_list_connected_peers_except_requester() {
+        let info = |outbound| PeerInfo {
+            version: PROTOCOL_VERSION,
+            services: NODE_NETWORK,
+            user_agent: String::from("/test/"),
+            start_height: 1,
+            verack: true,
+            outbound,
+            pending_ping: None,
+            latency: Some(Duration::from_millis(5)),
+            known_inventory: KnownInventory::default(),
+            tx_announcements: vec![],
+        };
+        let mut peer_info = HashMap::new();
+        peer_info.insert(String::from("localhost:3001"), info(true));
+        peer_info.insert(String::from("cli:3000"), info(false));
+
+        let peers = peer_summaries(&peer_info, "cli:3000");
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, "localhost:3001");
+        assert!(peers[0].outbound);
+        assert_eq!(peers[0].latency_ms, Some(5));
     }
 }
@@ -0,0 +1,376 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io,
+    net::TcpStream,
+    sync::{
+        Notify,
+        mpsc::{self, Receiver, Sender, error::TrySendError},
+    },
+};
+use tokio_util::codec::Framed;
+
+use crate::network::{FrameError, LengthHeaderDelimiter, command::Network};
+
+pub type SessionId = u64;
+
+/// 会话产生的事件, 统一交给节点主循环处理
+pub enum PeerEvent {
+    Package {
+        session: SessionHandle,
+        package: Vec<u8>,
+    },
+    // 收到无法解析的帧, 随后会话关闭
+    Invalid {
+        session: SessionHandle,
+        error: FrameError,
+    },
+    Closed(SessionId),
+}
+
+/// 交给会话任务的指令
+enum Outgoing {
+    Frame(Vec<u8>),
+    Close,
+}
+
+/// 向某个会话发送消息的句柄
+#[derive(Clone)]
+pub struct SessionHandle {
+    pub id: SessionId,
+    sender: Sender<Outgoing>,
+    // 发送队列已满时通知会话任务立即关闭
+    abort: Arc<Notify>,
+}
+
+impl SessionHandle {
+    /// 把序列化好的消息交给会话任务发送, 不等待网络写入
+    ///
+    /// 对方读取过慢导致发送队列已满时不再等待, 直接关闭该会话
+    pub fn send(&self, bytes: Vec<u8>) -> Result<(), io::Error> {
+        match self.sender.try_send(Outgoing::Frame(bytes)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.abort.notify_one();
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "peer send queue is full"))
+            }
+            Err(TrySendError::Closed(_)) => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "peer session is closed"))
+            }
+        }
+    }
+
+    /// 发送完已排队的消息后关闭会话, 发送队列已满时立即关闭
+    pub fn close(&self) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(Outgoing::Close) {
+            self.abort.notify_one();
+        }
+    }
+}
+
+/// 建立连接的超时时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 每个会话排队等待发送的消息数上限
+const MAX_QUEUED_MESSAGES: usize = 128;
+
+/// 每个节点记录的已知inventory数量上限
+const MAX_KNOWN_INVENTORY: usize = 5000;
+
+/// 对方已知的区块与交易hash, 超过上限时忘记最早记录的
+#[derive(Default)]
+pub struct KnownInventory {
+    ids: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl KnownInventory {
+    /// 记录对方已知id
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 是否为新记录的id
+    pub fn insert(&mut self, id: &str) -> bool {
+        if !self.ids.insert(id.to_string()) {
+            return false;
+        }
+        self.order.push_back(id.to_string());
+        if self.order.len() > MAX_KNOWN_INVENTORY
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.ids.remove(&oldest);
+        }
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains(id)
+    }
+}
+
+// 握手时对方声明的信息
+pub struct PeerInfo {
+    // 双方协商出的协议版本
+    pub version: u8,
+    pub services: u64,
+    pub user_agent: String,
+    // 握手时对方的主链高度
+    pub start_height: u32,
+    // 对方是否已确认我方的version
+    pub verack: bool,
+    // 是否由本节点主动连接
+    pub outbound: bool,
+    // 尚未收到pong的ping: (nonce, 发送时间)
+    pub pending_ping: Option<(u64, Instant)>,
+    // 最近一次测得的往返延迟
+    pub latency: Option<Duration>,
+    // 对方已有或已向其通告过的区块与交易
+    pub known_inventory: KnownInventory,
+    // 等待下一次批量通告的交易
+    pub tx_announcements: Vec<String>,
+}
+
+/// 会话及其对应的对方监听地址
+struct PeerSession {
+    handle: SessionHandle,
+    // 主动连接时为拨号地址, 接受的连接在握手后才确定
+    addr: Option<String>,
+    // 连接的对方IP, 主动连接在连接建立后才确定
+    ip: Option<IpAddr>,
+    inbound: bool,
+    opened_at: Instant,
+}
+
+/// 节点当前的所有会话, 按会话id索引, 每个会话最多对应一个地址, 在会话任务之间共享
+#[derive(Clone)]
+pub struct Peers {
+    network: Network,
+    sessions: Arc<Mutex<HashMap<SessionId, PeerSession>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Peers {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            sessions: Arc::default(),
+            next_id: Arc::default(),
+        }
+    }
+
+    fn new_session(
+        &self,
+        addr: Option<String>,
+        ip: Option<IpAddr>,
+        inbound: bool,
+    ) -> (SessionHandle, Receiver<Outgoing>) {
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_MESSAGES);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let handle = SessionHandle {
+            id,
+            sender,
+            abort: Arc::default(),
+        };
+        self.sessions.lock().unwrap().insert(
+            id,
+            PeerSession {
+                handle: handle.clone(),
+                addr,
+                ip,
+                inbound,
+                opened_at: Instant::now(),
+            },
+        );
+        (handle, receiver)
+    }
+
+    /// 为接受的连接启动会话任务, 收到对方的version前不关联地址
+    ///
+    /// # Arguments
+    ///
+    /// - `stream` (`TcpStream`) - 已建立的连接
+    /// - `events` (`Sender<PeerEvent>`) - 节点主循环的事件通道, 已满时会话暂停读取
+    pub fn accept(&self, stream: TcpStream, events: Sender<PeerEvent>) {
+        let ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        let (session, outgoing) = self.new_session(None, ip, true);
+        tokio::spawn(run_session(stream, self.network, session, outgoing, events));
+    }
+
+    /// 向addr发起连接并以addr登记会话, 连接建立前发送的消息会排队等待
+    ///
+    /// # Arguments
+    ///
+    /// - `addr` (`&str`) - 对方的监听地址
+    /// - `events` (`Sender<PeerEvent>`) - 节点主循环的事件通道, 已满时会话暂停读取
+    ///
+    /// # Returns
+    ///
+    /// - `SessionHandle` - 新会话的句柄
+    pub fn connect(&self, addr: &str, events: Sender<PeerEvent>) -> SessionHandle {
+        let (session, outgoing) = self.new_session(Some(addr.to_string()), None, false);
+
+        let addr = addr.to_string();
+        let peers = self.clone();
+        let handle = session.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+                Ok(Ok(stream)) => {
+                    if let Ok(peer_addr) = stream.peer_addr()
+                        && let Some(peer) = peers.sessions.lock().unwrap().get_mut(&handle.id)
+                    {
+                        peer.ip = Some(peer_addr.ip());
+                    }
+                    run_session(stream, peers.network, handle, outgoing, events).await
+                }
+                Ok(Err(err)) => {
+                    println!("Failed to connect to {}: {}", addr, err);
+                    let _ = events.send(PeerEvent::Closed(handle.id)).await;
+                }
+                Err(_) => {
+                    println!("Failed to connect to {}: timed out", addr);
+                    let _ = events.send(PeerEvent::Closed(handle.id)).await;
+                }
+            }
+        });
+
+        session
+    }
+
+    /// 把对方的监听地址关联到会话
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 该地址已属于其他会话, 或会话已关联了其他地址时为false
+    pub fn register(&self, addr: &str, session: &SessionHandle) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        let taken = sessions
+            .iter()
+            .any(|(id, peer)| *id != session.id && peer.addr.as_deref() == Some(addr));
+        let Some(peer) = sessions.get_mut(&session.id) else {
+            return false;
+        };
+        if taken || peer.addr.as_deref().is_some_and(|current| current != addr) {
+            return false;
+        }
+        peer.addr = Some(addr.to_string());
+        true
+    }
+
+    /// 关联到addr的会话
+    pub fn get(&self, addr: &str) -> Option<SessionHandle> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .find(|peer| peer.addr.as_deref() == Some(addr))
+            .map(|peer| peer.handle.clone())
+    }
+
+    /// 会话关联的对方地址, 接受的连接在握手完成前为None
+    pub fn addr_of(&self, id: SessionId) -> Option<String> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .and_then(|peer| peer.addr.clone())
+    }
+
+    /// 会话连接的对方IP, 主动连接在连接建立前为None
+    pub fn ip_of(&self, id: SessionId) -> Option<IpAddr> {
+        self.sessions.lock().unwrap().get(&id).and_then(|peer| peer.ip)
+    }
+
+    /// 接受的连接数, 包括尚未完成握手的
+    pub fn inbound_count(&self) -> usize {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.inbound)
+            .count()
+    }
+
+    /// 建立时间超过age的会话
+    pub fn opened_before(&self, age: Duration) -> Vec<SessionHandle> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.opened_at.elapsed() >= age)
+            .map(|peer| peer.handle.clone())
+            .collect()
+    }
+
+    /// 删除已关闭的会话
+    ///
+    /// # Returns
+    ///
+    /// - `Option<String>` - 会话关联的对方地址
+    pub fn remove(&self, id: SessionId) -> Option<String> {
+        self.sessions.lock().unwrap().remove(&id)?.addr
+    }
+}
+
+/// 会话任务: 把收到的消息转交节点主循环, 并发送排队的消息, 任一方向出错即关闭
+async fn run_session(
+    stream: TcpStream,
+    network: Network,
+    session: SessionHandle,
+    mut outgoing: Receiver<Outgoing>,
+    events: Sender<PeerEvent>,
+) {
+    let codec = LengthHeaderDelimiter::new(network);
+    let (mut sink, mut frames) = Framed::new(stream, codec).split();
+
+    loop {
+        tokio::select! {
+            frame = frames.next() => {
+                let package = match frame {
+                    Some(Ok(package)) => package,
+                    Some(Err(FrameError::Io(_))) | None => break,
+                    Some(Err(error)) => {
+                        let _ = events
+                            .send(PeerEvent::Invalid {
+                                session: session.clone(),
+                                error,
+                            })
+                            .await;
+                        break;
+                    }
+                };
+                let event = PeerEvent::Package {
+                    session: session.clone(),
+                    package,
+                };
+                // 主循环处理不过来时在此等待, 暂停读取该连接
+                if events.send(event).await.is_err() {
+                    break;
+                }
+            }
+            Some(message) = outgoing.recv() => {
+                let Outgoing::Frame(bytes) = message else {
+                    break;
+                };
+                if sink.send(BytesMut::from(&bytes[..])).await.is_err() {
+                    break;
+                }
+            }
+            _ = session.abort.notified() => {
+                println!("Session {} send queue is full", session.id);
+                break;
+            }
+        }
+    }
+
+    let _ = events.send(PeerEvent::Closed(session.id)).await;
+}
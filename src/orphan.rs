@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::block::Block;
+
+// 孤块池最多保存的区块数
+pub const MAX_ORPHAN_BLOCKS: usize = 100;
+// 单个节点最多占用的孤块数
+pub const MAX_ORPHANS_PER_PEER: usize = 20;
+// 孤块在池中保留的最长时间
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+/// 父区块未知的区块
+struct OrphanBlock {
+    block: Block,
+    // 发送该区块的节点
+    peer: String,
+    received_at: Instant,
+}
+
+/// 孤块池, 保存父区块尚未到达的区块, 祖先区块接入后再依次接入
+#[derive(Default)]
+pub struct OrphanPool {
+    // hash -> OrphanBlock
+    orphans: HashMap<String, OrphanBlock>,
+    // prev_hash -> 以其为父区块的孤块hash
+    children: HashMap<String, Vec<String>>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 加入一个孤块, 调用方需先完成不依赖链上数据的校验
+    ///
+    /// 该节点的孤块达到MAX_ORPHANS_PER_PEER时淘汰它最早发送的孤块, 池满时淘汰最早收到的孤块
+    ///
+    /// # Arguments
+    ///
+    /// - `peer` (`&str`) - 发送该区块的节点
+    /// - `block` (`Block`) - 父区块未知的区块
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 是否为新加入的孤块
+    pub fn add(&mut self, peer: &str, block: Block) -> bool {
+        if self.orphans.contains_key(&block.hash) {
+            return false;
+        }
+
+        let from_peer = self.orphans.values().filter(|orphan| orphan.peer == peer).count();
+        if from_peer >= MAX_ORPHANS_PER_PEER {
+            self.remove_oldest(|orphan| orphan.peer == peer);
+        }
+        if self.orphans.len() >= MAX_ORPHAN_BLOCKS {
+            self.remove_oldest(|_| true);
+        }
+
+        self.children
+            .entry(block.header.prev_hash.clone())
+            .or_default()
+            .push(block.hash.clone());
+        self.orphans.insert(
+            block.hash.clone(),
+            OrphanBlock {
+                block,
+                peer: peer.to_string(),
+                received_at: Instant::now(),
+            },
+        );
+        true
+    }
+
+    /// 淘汰满足条件的孤块中最早收到的一个
+    fn remove_oldest(&mut self, filter: impl Fn(&OrphanBlock) -> bool) {
+        let oldest = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| filter(orphan))
+            .min_by_key(|(_, orphan)| orphan.received_at)
+            .map(|(hash, _)| hash.clone());
+        if let Some(oldest) = oldest {
+            self.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, hash: &str) -> Option<OrphanBlock> {
+        let orphan = self.orphans.remove(hash)?;
+        let prev_hash = &orphan.block.header.prev_hash;
+        if let Some(siblings) = self.children.get_mut(prev_hash) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.children.remove(prev_hash);
+            }
+        }
+        Some(orphan)
+    }
+
+    /// 取出以parent_hash为父区块的孤块
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<(String, Block)>` - (发送节点, 区块)
+    pub fn take_children(&mut self, parent_hash: &str) -> Vec<(String, Block)> {
+        let Some(hashes) = self.children.remove(parent_hash) else {
+            return vec![];
+        };
+
+        hashes
+            .into_iter()
+            .filter_map(|hash| self.orphans.remove(&hash))
+            .map(|orphan| (orphan.peer, orphan.block))
+            .collect()
+    }
+
+    /// 丢弃建立在无效区块之上的所有孤块
+    pub fn discard_descendants(&mut self, hash: &str) {
+        let mut invalid = vec![hash.to_string()];
+        while let Some(parent_hash) = invalid.pop() {
+            for (_, child) in self.take_children(&parent_hash) {
+                invalid.push(child.hash);
+            }
+        }
+    }
+
+    /// 删除超过ORPHAN_EXPIRY仍未接入的孤块
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| now.duration_since(orphan.received_at) >= ORPHAN_EXPIRY)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+
+        for hash in expired {
+            println!("Orphan block {} expired", &hash);
+            self.remove(&hash);
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::block;
+
+    #[test]
+    fn take_children_returns_orphans_of_parent() {
+        let mut pool = OrphanPool::new();
+        assert!(pool.add("peer", block("b", "a")));
+        assert!(pool.add("peer", block("c", "b")));
+        assert!(!pool.add("peer", block("b", "a")));
+        assert_eq!(pool.len(), 2);
+
+        let children = pool.take_children("a");
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].0, "peer");
+        assert_eq!(children[0].1.hash, "b");
+        assert!(!pool.contains("b"));
+        assert!(pool.take_children("a").is_empty());
+        assert!(pool.contains("c"));
+    }
+
+    #[test]
+    fn discard_descendants_drops_whole_branch() {
+        let mut pool = OrphanPool::new();
+        pool.add("peer", block("b", "a"));
+        pool.add("peer", block("c", "b"));
+        pool.add("peer", block("d", "c"));
+        pool.add("peer", block("x", "other"));
+
+        pool.discard_descendants("a");
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains("x"));
+    }
+
+    #[test]
+    fn add_evicts_oldest_orphan_of_the_same_peer() {
+        let mut pool = OrphanPool::new();
+        pool.add("honest", block("h", "a"));
+        for i in 0..MAX_ORPHANS_PER_PEER + 5 {
+            pool.add("spammer", block(&format!("s{}", i), "a"));
+        }
+
+        assert_eq!(pool.len(), MAX_ORPHANS_PER_PEER + 1);
+        assert!(pool.contains("h"));
+        assert!(!pool.contains("s0"));
+        assert!(pool.contains(&format!("s{}", MAX_ORPHANS_PER_PEER + 4)));
+    }
+
+    #[test]
+    fn add_evicts_oldest_orphan_when_full() {
+        let mut pool = OrphanPool::new();
+        for i in 0..MAX_ORPHAN_BLOCKS + 1 {
+            pool.add(&format!("peer{}", i), block(&format!("b{}", i), "a"));
+        }
+
+        assert_eq!(pool.len(), MAX_ORPHAN_BLOCKS);
+        assert!(!pool.contains("b0"));
+        assert!(pool.contains(&format!("b{}", MAX_ORPHAN_BLOCKS)));
+        assert_eq!(pool.take_children("a").len(), MAX_ORPHAN_BLOCKS);
+    }
+}
@@ -1,49 +1,111 @@
 #![allow(dead_code)]
-use crate::block::Block;
+use crate::block::BlockHeader;
 use ethereum_types::U256;
 use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 // max difficulty is 255
-const DIFFICULTY: u8 = 1;
+pub const INITIAL_DIFFICULTY: u8 = 1;
+pub const MIN_DIFFICULTY: u8 = 1;
+// 每隔多少个区块重新计算一次难度
+pub const RETARGET_INTERVAL: u128 = 10;
+// 期望的出块间隔, 毫秒
+pub const TARGET_BLOCK_TIME: u128 = 10_000;
+// 单次调整最多变化的难度位数(每一位代表2倍的工作量)
+pub const MAX_RETARGET_STEP: u8 = 2;
 
 #[derive(Debug)]
 pub struct ProofOfWork<'a> {
     pub target: U256,
-    pub block: &'a Block,
+    pub header: &'a BlockHeader,
 }
 
 impl<'a> ProofOfWork<'a> {
-    pub fn new(block: &'a Block) -> Self {
-        let mut target = U256::from(1);
-        target = target << (256u32 - DIFFICULTY as u32);
-        ProofOfWork { target, block }
+    pub fn new(header: &'a BlockHeader) -> Self {
+        let target = ProofOfWork::target(header.bits);
+        ProofOfWork { target, header }
+    }
+
+    /// 根据难度计算目标值, 哈希需小于该值
+    pub fn target(difficulty: u8) -> U256 {
+        U256::from(1) << (256u32 - difficulty as u32)
+    }
+
+    /// 单个区块代表的工作量, 即期望的哈希次数
+    pub fn work(difficulty: u8) -> U256 {
+        U256::from(1) << difficulty as u32
+    }
+
+    /// 根据上一个窗口的实际耗时重新计算难度
+    ///
+    /// # Arguments
+    ///
+    /// - `prev_difficulty` (`u8`) - 上一个窗口的难度
+    /// - `actual_timespan` (`u128`) - 窗口内首尾区块的时间差, 毫秒
+    ///
+    /// # Returns
+    ///
+    /// - `u8` - 新的难度
+    pub fn retarget(prev_difficulty: u8, actual_timespan: u128) -> u8 {
+        let expected_timespan = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1);
+        let mut difficulty = prev_difficulty;
+        let mut timespan = actual_timespan;
+        let mut step = 0u8;
+
+        // 出块过快, 提高难度
+        while timespan * 2 <= expected_timespan && step < MAX_RETARGET_STEP {
+            difficulty = difficulty.saturating_add(1);
+            timespan = (timespan * 2).max(1);
+            step += 1;
+        }
+        // 出块过慢, 降低难度
+        while timespan >= expected_timespan * 2 && step < MAX_RETARGET_STEP {
+            difficulty = difficulty.saturating_sub(1);
+            timespan /= 2;
+            step += 1;
+        }
+
+        difficulty.max(MIN_DIFFICULTY)
     }
 
     pub fn init_data(&self, nonce: &u32) -> Vec<u8> {
-        // prev_hash data nonce difficulty
+        // version prev_hash merkle_root timestamp bits nonce
         let mut hash: Vec<u8> = Vec::new();
-        let prev_hash_bytes = hex::decode(&self.block.prev_hash)
+        let prev_hash_bytes = hex::decode(&self.header.prev_hash)
             .expect("Failed to decode block's prev_hash from hex string");
+        let merkle_root_bytes = hex::decode(&self.header.merkle_root)
+            .expect("Failed to decode block's merkle_root from hex string");
+        hash.extend(self.header.version.to_be_bytes());
         hash.extend(prev_hash_bytes);
-        hash.extend(&self.block.hash_transactions());
+        hash.extend(merkle_root_bytes);
+        hash.extend(self.header.timestamp.to_be_bytes());
+        hash.extend(self.header.bits.to_be_bytes());
         hash.extend(nonce.to_be_bytes());
-        hash.extend(DIFFICULTY.to_be_bytes());
 
         hash
     }
 
+    fn calculate_hash(&self, nonce: &u32) -> Vec<u8> {
+        let data_to_hash = &self.init_data(nonce);
+        let mut hasher = Sha256::new();
+        hasher.update(data_to_hash);
+        hasher.finalize().to_vec()
+    }
+
     pub fn run(&self) -> Option<(u32, String)> {
+        self.run_until(&AtomicBool::new(false))
+    }
+
+    /// 计算满足目标的nonce, cancel被置为true时提前放弃
+    pub fn run_until(&self, cancel: &AtomicBool) -> Option<(u32, String)> {
         let mut nonce = 1u32;
 
         let hash = loop {
-            if nonce == u32::MAX {
+            if nonce == u32::MAX || cancel.load(Ordering::Relaxed) {
                 break None;
             }
 
-            let data_to_hash = &self.init_data(&nonce);
-            let mut hasher = Sha256::new();
-            hasher.update(data_to_hash);
-            let hash = hasher.finalize().to_vec();
+            let hash = self.calculate_hash(&nonce);
             if U256::from_big_endian(&hash) < self.target {
                 break Some(hash);
             }
@@ -57,13 +119,58 @@ impl<'a> ProofOfWork<'a> {
         }
     }
 
-    pub fn validate(&self) -> bool {
-        let nonce = self.block.nonce;
-        let data_to_hash = &self.init_data(&nonce);
-        let mut hasher = sha2::Sha256::new();
-        hasher.update(data_to_hash);
-        let hash = hasher.finalize().to_vec();
+    /// 按区块头当前的nonce重新计算哈希
+    pub fn hash(&self) -> String {
+        hex::encode(self.calculate_hash(&self.header.nonce))
+    }
+
+    /// 校验区块的工作量证明
+    ///
+    /// # Arguments
+    ///
+    /// - `difficulty` (`u8`) - 链规则要求该区块具备的难度
+    ///
+    /// # Returns
+    ///
+    /// - `bool` - 是否通过校验
+    pub fn validate(&self, difficulty: u8) -> bool {
+        if self.header.bits != difficulty {
+            return false;
+        }
+
+        let hash = self.calculate_hash(&self.header.nonce);
 
         U256::from_big_endian(&hash) < self.target
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXPECTED_TIMESPAN: u128 = TARGET_BLOCK_TIME * (RETARGET_INTERVAL - 1);
+
+    #[test]
+    fn retarget_keeps_difficulty_on_target() {
+        assert_eq!(ProofOfWork::retarget(5, EXPECTED_TIMESPAN), 5);
+        // 偏差不足2倍时不调整
+        assert_eq!(ProofOfWork::retarget(5, EXPECTED_TIMESPAN * 3 / 2), 5);
+        assert_eq!(ProofOfWork::retarget(5, EXPECTED_TIMESPAN * 2 / 3), 5);
+    }
+
+    #[test]
+    fn retarget_raises_difficulty_when_blocks_are_fast() {
+        assert_eq!(ProofOfWork::retarget(5, EXPECTED_TIMESPAN / 2), 6);
+        // 单次最多调整MAX_RETARGET_STEP位
+        assert_eq!(ProofOfWork::retarget(5, 0), 5 + MAX_RETARGET_STEP);
+        assert_eq!(ProofOfWork::retarget(u8::MAX, 0), u8::MAX);
+    }
+
+    #[test]
+    fn retarget_lowers_difficulty_when_blocks_are_slow() {
+        assert_eq!(ProofOfWork::retarget(5, EXPECTED_TIMESPAN * 2), 4);
+        assert_eq!(ProofOfWork::retarget(5, EXPECTED_TIMESPAN * 100), 5 - MAX_RETARGET_STEP);
+        // 不低于MIN_DIFFICULTY
+        assert_eq!(ProofOfWork::retarget(MIN_DIFFICULTY, EXPECTED_TIMESPAN * 100), MIN_DIFFICULTY);
+    }
+}
@@ -0,0 +1,251 @@
+use std::{collections::BTreeMap, fmt::Display, path::Path, sync::RwLock};
+
+/// 存储层错误
+#[derive(Debug)]
+pub struct StorageError(String);
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "storage error: {}", self.0)
+    }
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(err: sled::Error) -> Self {
+        StorageError(err.to_string())
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// 前缀扫描结果, 按key升序
+pub type ScanIter<'a> = Box<dyn Iterator<Item = StorageResult<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// 一组原子提交的写操作, 按加入顺序生效
+#[derive(Default)]
+pub struct WriteBatch {
+    // value为None表示删除
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl WriteBatch {
+    pub fn put(&mut self, key: impl AsRef<[u8]>, value: Vec<u8>) {
+        self.ops.push((key.as_ref().to_vec(), Some(value)));
+    }
+
+    pub fn delete(&mut self, key: impl AsRef<[u8]>) {
+        self.ops.push((key.as_ref().to_vec(), None));
+    }
+}
+
+/// Blockchain使用的KV存储
+pub trait Storage: Send + Sync {
+    fn get(&self, key: &[u8]) -> StorageResult<Option<Vec<u8>>>;
+
+    fn put(&self, key: &[u8], value: Vec<u8>) -> StorageResult<()>;
+
+    fn delete(&self, key: &[u8]) -> StorageResult<()>;
+
+    fn contains_key(&self, key: &[u8]) -> StorageResult<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// 原子地提交batch, 要么全部生效要么全部不生效
+    fn apply_batch(&self, batch: WriteBatch) -> StorageResult<()>;
+
+    fn scan_prefix(&self, prefix: &[u8]) -> ScanIter<'_>;
+
+    /// 持久化尚未落盘的写入
+    fn flush(&self) -> StorageResult<()>;
+}
+
+/// 基于sled的磁盘存储
+pub struct SledStorage {
+    db: sled::Db,
+}
+
+impl SledStorage {
+    pub fn open(path: impl AsRef<Path>) -> StorageResult<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+}
+
+impl Storage for SledStorage {
+    fn get(&self, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|val| val.to_vec()))
+    }
+
+    fn put(&self, key: &[u8], value: Vec<u8>) -> StorageResult<()> {
+        self.db.insert(key, value)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> StorageResult<()> {
+        self.db.remove(key)?;
+        Ok(())
+    }
+
+    fn contains_key(&self, key: &[u8]) -> StorageResult<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> StorageResult<()> {
+        let mut sled_batch = sled::Batch::default();
+        for (key, value) in batch.ops {
+            match value {
+                Some(value) => sled_batch.insert(key, value),
+                None => sled_batch.remove(key),
+            }
+        }
+        self.db.apply_batch(sled_batch)?;
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> ScanIter<'_> {
+        Box::new(self.db.scan_prefix(prefix).map(|result| {
+            let (key, val) = result?;
+            Ok((key.to_vec(), val.to_vec()))
+        }))
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// 纯内存存储, 进程退出即丢失, 用于测试
+#[derive(Default)]
+pub struct MemoryStorage {
+    map: RwLock<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        Ok(self.map.read().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &[u8], value: Vec<u8>) -> StorageResult<()> {
+        self.map.write().unwrap().insert(key.to_vec(), value);
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> StorageResult<()> {
+        self.map.write().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> StorageResult<()> {
+        let mut map = self.map.write().unwrap();
+        for (key, value) in batch.ops {
+            match value {
+                Some(value) => map.insert(key, value),
+                None => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> ScanIter<'_> {
+        // 返回快照, 扫描期间允许修改
+        let entries: Vec<_> = self
+            .map
+            .read()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, val)| Ok((key.clone(), val.clone())))
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+}
+
+/// 在底层存储之上暂存写入, 读取时优先返回暂存的修改
+///
+/// 用于需要边写边读的多步修改(例如链重组), 全部成功后通过into_batch一次性提交,
+/// 中途放弃时底层存储不受影响
+pub struct StagedStorage<'a> {
+    base: &'a dyn Storage,
+    // value为None表示删除
+    staged: RwLock<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl<'a> StagedStorage<'a> {
+    pub fn new(base: &'a dyn Storage) -> Self {
+        Self {
+            base,
+            staged: RwLock::default(),
+        }
+    }
+
+    /// 把所有暂存的修改转换为可原子提交到底层存储的batch
+    pub fn into_batch(self) -> WriteBatch {
+        let ops = self.staged.into_inner().unwrap().into_iter().collect();
+        WriteBatch { ops }
+    }
+}
+
+impl Storage for StagedStorage<'_> {
+    fn get(&self, key: &[u8]) -> StorageResult<Option<Vec<u8>>> {
+        match self.staged.read().unwrap().get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.base.get(key),
+        }
+    }
+
+    fn put(&self, key: &[u8], value: Vec<u8>) -> StorageResult<()> {
+        self.staged.write().unwrap().insert(key.to_vec(), Some(value));
+        Ok(())
+    }
+
+    fn delete(&self, key: &[u8]) -> StorageResult<()> {
+        self.staged.write().unwrap().insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> StorageResult<()> {
+        self.staged.write().unwrap().extend(batch.ops);
+        Ok(())
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> ScanIter<'_> {
+        let mut entries = BTreeMap::new();
+        for result in self.base.scan_prefix(prefix) {
+            match result {
+                Ok((key, val)) => entries.insert(key, val),
+                Err(err) => return Box::new(std::iter::once(Err(err))),
+            };
+        }
+
+        // 暂存的修改覆盖底层存储中的记录
+        let staged = self.staged.read().unwrap();
+        for (key, value) in staged
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+        {
+            match value {
+                Some(val) => entries.insert(key.clone(), val.clone()),
+                None => entries.remove(key),
+            };
+        }
+
+        Box::new(entries.into_iter().map(Ok))
+    }
+
+    /// 暂存的修改只在提交into_batch后落盘
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+use crate::block::{BLOCK_VERSION, Block, BlockHeader};
+
+/// 只有hash与父区块hash的区块, 用于不校验区块内容的测试
+pub fn block(hash: &str, prev_hash: &str) -> Block {
+    Block {
+        header: BlockHeader {
+            version: BLOCK_VERSION,
+            prev_hash: prev_hash.to_string(),
+            merkle_root: String::default(),
+            timestamp: 0,
+            bits: 1,
+            nonce: 0,
+        },
+        transactions: vec![],
+        hash: hash.to_string(),
+        height: 0,
+    }
+}
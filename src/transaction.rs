@@ -11,6 +11,7 @@ use k256::ecdsa::Signature;
 use k256::ecdsa::VerifyingKey;
 use k256::ecdsa::signature::SignerMut;
 use k256::ecdsa::signature::Verifier;
+use k256::elliptic_curve::rand_core::{OsRng, RngCore};
 use sha2::Digest;
 use sha2::Sha256;
 
@@ -19,6 +20,28 @@ use crate::tx::TxOutput;
 use crate::utxo::UTXOSet;
 use crate::wallet::Wallet;
 
+/// 交易无法签名的原因
+#[derive(Debug)]
+pub enum SignError {
+    // Input引用的Tx不在主链上
+    MissingPrevTx(String),
+    // Input引用的Output下标超出了Tx的Output数量
+    MissingOutput { tx_id: String, out_idx: usize },
+}
+
+impl Display for SignError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignError::MissingPrevTx(tx_id) => {
+                write!(f, "referenced transaction {} is not on the main chain", tx_id)
+            }
+            SignError::MissingOutput { tx_id, out_idx } => {
+                write!(f, "transaction {} has no output {}", tx_id, out_idx)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Encode, Decode, Clone)]
 pub struct Transaction {
     pub id: Vec<u8>,
@@ -33,7 +56,7 @@ impl AsRef<Transaction> for &mut Transaction {
 }
 
 impl Transaction {
-    fn hash(&self) -> Vec<u8> {
+    pub fn hash(&self) -> Vec<u8> {
         let inputs = self
             .inputs
             .iter()
@@ -80,25 +103,39 @@ impl Transaction {
         self.inputs.len() == 1 && self.inputs[0].tx_id.len() == 0
     }
 
+    /// 解析coinbase输入中承诺的区块高度
+    pub fn coinbase_height(&self) -> Option<u128> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let bytes = self.inputs[0].pub_key.get(..16)?;
+        Some(u128::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
     /// 生成coinbase transaction
     ///
     /// # Arguments
     ///
     /// - `to` (`String`) - coin receiver
+    /// - `height` (`u128`) - 所在区块的高度
+    /// - `reward` (`u128`) - 挖矿奖励与区块内交易手续费之和
     ///
     /// # Returns
     ///
     /// - `Self` - Transaction
     /// ```
-    pub fn coinbase_tx(to: String) -> Self {
-        let tx_input = TxInput::new(
-            Vec::default(),
-            0,
-            String::from("Coinbase").as_bytes().to_vec(),
-            Vec::new(),
-        );
-
-        let tx_output = TxOutput::new(100, to);
+    pub fn coinbase_tx(to: String, height: u128, reward: u128) -> Self {
+        // 输入承诺区块高度与随机数据, 保证不同区块的coinbase id不重复
+        let mut extra_nonce = [0u8; 8];
+        OsRng.fill_bytes(&mut extra_nonce);
+
+        let mut coinbase_data = height.to_be_bytes().to_vec();
+        coinbase_data.extend_from_slice(&extra_nonce);
+        coinbase_data.extend_from_slice(String::from("Coinbase").as_bytes());
+
+        let tx_input = TxInput::new(Vec::default(), 0, coinbase_data, Vec::new());
+
+        let tx_output = TxOutput::new(reward, to);
         let mut tx = Transaction {
             id: Vec::default(),
             inputs: vec![tx_input],
@@ -111,9 +148,28 @@ impl Transaction {
         tx
     }
 
-    pub async fn new(from_wallet: &mut Wallet, to: &str, amount: u128, utxo_set: &mut UTXOSet) -> Self {
+    /// 生成转账transaction, 输入总额减去输出总额即为手续费
+    ///
+    /// # Arguments
+    ///
+    /// - `from_wallet` (`&mut Wallet`) - 付款钱包
+    /// - `to` (`&str`) - 收款地址
+    /// - `amount` (`u128`) - 转账金额
+    /// - `fee` (`u128`) - 支付给矿工的手续费
+    /// - `utxo_set` (`&mut UTXOSet`) - UTXO set
+    ///
+    /// # Returns
+    ///
+    /// - `Result<Self, SignError>` - 已签名的Transaction, 或无法签名的原因
+    pub async fn new(
+        from_wallet: &mut Wallet,
+        to: &str,
+        amount: u128,
+        fee: u128,
+        utxo_set: &mut UTXOSet,
+    ) -> Result<Self, SignError> {
         let (accumulated, valid_outputs) = utxo_set
-            .find_spendable_outputs(&Wallet::hash_pub_key(&from_wallet.pub_key), amount)
+            .find_spendable_outputs(&Wallet::hash_pub_key(&from_wallet.pub_key), amount + fee)
             .await
             .expect(&format!(
                 "Address [{}] does'nt have enough money!",
@@ -140,8 +196,8 @@ impl Transaction {
         let to_output = TxOutput::new(amount, to.to_string());
         outputs.push(to_output);
 
-        if accumulated > amount {
-            let remain_output = TxOutput::new(accumulated - amount, from_wallet.address());
+        if accumulated > amount + fee {
+            let remain_output = TxOutput::new(accumulated - amount - fee, from_wallet.address());
             outputs.push(remain_output);
         }
 
@@ -156,9 +212,10 @@ impl Transaction {
 
         utxo_set
             .blockchain
-            .sign_transaction(&mut tx, &mut from_wallet.priv_key).await;
+            .sign_transaction(&mut tx, &mut from_wallet.priv_key)
+            .await?;
 
-        tx
+        Ok(tx)
     }
 
     /// 生成干净的只用于生成签名的Transaction
@@ -209,25 +266,27 @@ impl Transaction {
     /// - `signingKey` (`ecdsa`) - 签名Key
     /// - `prevTxs` (`HashMap<String, Transaction>`) - 签名用到的当前Tx的inputs关联的Tx.
     ///
-    /// # Examples
-    ///
-    /// ```
-    /// use crate::...;
+    /// # Returns
     ///
-    /// let _ = sign();
-    /// ```
+    /// - `Result<(), SignError>` - 缺少关联的Tx或Output时不做任何修改
     pub fn sign(
         &mut self,
         signing_key: &mut ecdsa::SigningKey,
         mut prev_txs: HashMap<String, Transaction>,
-    ) {
+    ) -> Result<(), SignError> {
         let mut tx_copy = self.trimmed_copy();
 
         // 检验所有的input 关联Tx
         for input in &tx_copy.inputs {
             let tx_id = hex::encode(&input.tx_id);
-            if !prev_txs.contains_key(&tx_id) {
-                panic!("未找到Input关联的目标Tx: {}", tx_id);
+            let Some(prev_tx) = prev_txs.get(&tx_id) else {
+                return Err(SignError::MissingPrevTx(tx_id));
+            };
+            if input.out_idx >= prev_tx.outputs.len() {
+                return Err(SignError::MissingOutput {
+                    tx_id,
+                    out_idx: input.out_idx,
+                });
             }
         }
 
@@ -238,7 +297,7 @@ impl Transaction {
                 let input = tx_copy.inputs.get_mut(idx).unwrap();
                 let tx_id = hex::encode(&input.tx_id);
                 let prev_tx = prev_txs.get_mut(&tx_id).unwrap();
-                input.pub_key = prev_tx.outputs.remove(input.out_idx).pub_key_hash;
+                input.pub_key = prev_tx.outputs[input.out_idx].pub_key_hash.clone();
             }
 
             let encoded_tx = bincode::encode_to_vec(&tx_copy, standard()).unwrap();
@@ -248,6 +307,8 @@ impl Transaction {
             let input = self.inputs.get_mut(idx).unwrap();
             input.sig = signature.to_vec();
         }
+
+        Ok(())
     }
 
     /// 校验Transaction是否
@@ -264,10 +325,10 @@ impl Transaction {
             return true;
         }
 
+        // 缺少关联的Tx视为校验失败
         for input in &self.inputs {
-            let tx_id = hex::encode(&input.tx_id);
-            if !prev_txs.contains_key(&tx_id) {
-                panic!("未找到Input关联的Tx: {}", tx_id);
+            if !prev_txs.contains_key(&hex::encode(&input.tx_id)) {
+                return false;
             }
         }
 
@@ -276,15 +337,31 @@ impl Transaction {
         for (idx, input) in self.inputs.iter().enumerate() {
             let tx_id = hex::encode(&input.tx_id);
             let prev_tx = prev_txs.get_mut(&tx_id).unwrap();
-            tx_copy.inputs[idx].pub_key = prev_tx.outputs.remove(input.out_idx).pub_key_hash;
+            if input.out_idx >= prev_tx.outputs.len() {
+                return false;
+            }
+            // 签名只能证明持有input.pub_key, 还需确认该公钥就是Output的所有者
+            if !input.spent_by(&prev_tx.outputs[input.out_idx].pub_key_hash) {
+                return false;
+            }
+            tx_copy.inputs[idx].pub_key = prev_tx.outputs[input.out_idx].pub_key_hash.clone();
 
             let encoded_tx = bincode::encode_to_vec(&tx_copy, standard()).unwrap();
             let hash = Sha256::digest(encoded_tx);
 
-            let encoded_point = EncodedPoint::from_bytes(&input.pub_key).unwrap();
-            let verifying_key = VerifyingKey::from_encoded_point(&encoded_point).unwrap();
-            let raw_bytes: [u8; 64] = input.sig.clone().try_into().unwrap();
-            let signature = Signature::from_bytes(&raw_bytes.into()).unwrap();
+            // 来自网络的Tx可能携带任意字节, 格式错误视为校验失败
+            let Ok(encoded_point) = EncodedPoint::from_bytes(&input.pub_key) else {
+                return false;
+            };
+            let Ok(verifying_key) = VerifyingKey::from_encoded_point(&encoded_point) else {
+                return false;
+            };
+            let Ok(raw_bytes) = <[u8; 64]>::try_from(input.sig.clone()) else {
+                return false;
+            };
+            let Ok(signature) = Signature::from_bytes(&raw_bytes.into()) else {
+                return false;
+            };
             if let Err(_) = verifying_key.verify(&hash, &signature) {
                 return false;
             }
@@ -317,3 +394,46 @@ impl Display for Transaction {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 用wallet的私钥签名一笔花费prev_tx第0个Output的交易
+    fn spend(prev_tx: &Transaction, wallet: &mut Wallet, to: &str) -> Transaction {
+        let input = TxInput::new(prev_tx.id.clone(), 0, wallet.pub_key.clone(), vec![]);
+        let mut tx = Transaction {
+            id: vec![],
+            inputs: vec![input],
+            outputs: vec![TxOutput::new(prev_tx.outputs[0].amount, to.to_string())],
+        };
+        tx.id = tx.hash();
+
+        let prev_txs = HashMap::from([(hex::encode(&prev_tx.id), prev_tx.clone())]);
+        tx.sign(&mut wallet.priv_key, prev_txs).unwrap();
+        tx
+    }
+
+    #[test]
+    fn verity_accepts_spend_by_owner() {
+        let mut owner = Wallet::new();
+        let prev_tx = Transaction::coinbase_tx(owner.address(), 1, 100);
+        let tx = spend(&prev_tx, &mut owner, &Wallet::new().address());
+
+        let prev_txs = HashMap::from([(hex::encode(&prev_tx.id), prev_tx)]);
+        assert!(tx.verity(prev_txs));
+    }
+
+    #[test]
+    fn verity_rejects_spending_others_output() {
+        let owner = Wallet::new();
+        let mut thief = Wallet::new();
+        let prev_tx = Transaction::coinbase_tx(owner.address(), 1, 100);
+        // 签名本身合法, 但thief的公钥不是Output的所有者
+        let to = thief.address();
+        let tx = spend(&prev_tx, &mut thief, &to);
+
+        let prev_txs = HashMap::from([(hex::encode(&prev_tx.id), prev_tx)]);
+        assert!(!tx.verity(prev_txs));
+    }
+}
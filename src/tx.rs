@@ -20,25 +20,36 @@ pub struct TxOutput {
     pub pub_key_hash: Vec<u8>,
 }
 
-#[derive(Debug, Decode, Encode)]
-pub struct TxOutputs {
-    pub outputs: Vec<TxOutput>
+// coinbase产出的Output需要经过多少个区块才能花费
+pub const COINBASE_MATURITY: u128 = 10;
+
+/// 未花费的TxOutput及其来源
+#[derive(Debug, Encode, Decode, Clone)]
+pub struct Utxo {
+    pub output: TxOutput,
+    pub height: u128,
+    pub coinbase: bool,
 }
 
-impl TxOutputs {
-    pub fn new(outputs: Vec<TxOutput>) -> Self {
-        Self { outputs }
+impl Utxo {
+    /// 在spend_height高度的区块中是否可以花费
+    pub fn is_mature(&self, spend_height: u128) -> bool {
+        !self.coinbase || spend_height >= self.height + COINBASE_MATURITY
     }
+}
 
-    pub fn push(&mut self, output: TxOutput) {
-        self.outputs.push(output);
-    }
+/// 被某个区块花费掉的UTXO, 回滚时按原outpoint恢复
+#[derive(Debug, Encode, Decode, Clone)]
+pub struct SpentOutput {
+    pub tx_id: Vec<u8>,
+    pub out_idx: usize,
+    pub utxo: Utxo,
 }
 
-impl Default for TxOutputs {
-    fn default() -> Self {
-        Self { outputs: Default::default() }
-    }
+/// 区块的undo记录, 按花费顺序保存该区块消耗的所有UTXO
+#[derive(Debug, Encode, Decode, Default)]
+pub struct BlockUndo {
+    pub spent: Vec<SpentOutput>,
 }
 
 
@@ -78,3 +89,31 @@ impl TxInput {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utxo(height: u128, coinbase: bool) -> Utxo {
+        Utxo {
+            output: TxOutput {
+                amount: 10,
+                pub_key_hash: vec![],
+            },
+            height,
+            coinbase,
+        }
+    }
+
+    #[test]
+    fn coinbase_matures_after_coinbase_maturity_blocks() {
+        let coinbase = utxo(5, true);
+        assert!(!coinbase.is_mature(5));
+        assert!(!coinbase.is_mature(5 + COINBASE_MATURITY - 1));
+        assert!(coinbase.is_mature(5 + COINBASE_MATURITY));
+    }
+
+    #[test]
+    fn regular_output_is_always_mature() {
+        assert!(utxo(5, false).is_mature(5));
+    }
+}
@@ -1,14 +1,21 @@
-use std::{collections::HashMap, rc::Rc};
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
 use bincode::config::{self, standard};
 
 use crate::{
     block::Block,
     blockchain::Blockchain,
-    tx::{TxOutput, TxOutputs},
+    storage::{Storage, WriteBatch},
+    tx::{BlockUndo, SpentOutput, TxOutput, Utxo},
 };
 
 const UTXO_PREFIX: &str = "utxo-";
+const UNDO_PREFIX: &str = "undo-";
+// UTXO set已接入的最后一个区块, 不能以UTXO_PREFIX开头
+const UTXO_TIP_KEY: &str = "utip";
 
 pub struct UTXOSet {
     pub blockchain: Rc<Blockchain>,
@@ -18,6 +25,21 @@ impl<'a> UTXOSet {
     pub fn new(blockchain: Rc<Blockchain>) -> Self {
         Self { blockchain }
     }
+
+    /// 每个Output(tx_id, out_idx)单独存储一条记录
+    fn utxo_key(tx_id: &str, out_idx: usize) -> String {
+        format!("{}{}:{}", UTXO_PREFIX, tx_id, out_idx)
+    }
+
+    /// 从存储key中解析出(tx_id, out_idx)
+    fn parse_utxo_key(key: &[u8]) -> (String, usize) {
+        let outpoint = String::from_utf8_lossy(&key[UTXO_PREFIX.len()..]).to_string();
+        let (tx_id, out_idx) = outpoint
+            .split_once(':')
+            .unwrap_or_else(|| panic!("Invalid UTXO key {}", outpoint));
+        (tx_id.to_string(), out_idx.parse().unwrap())
+    }
+
     /// 找到足够amount的可花费Txoutput
     ///
     /// # Arguments
@@ -36,6 +58,8 @@ impl<'a> UTXOSet {
     ) -> Option<(u128, HashMap<String, Vec<usize>>)> {
         let mut accumulated = 0u128;
         let mut spendable_outputs = HashMap::<String, Vec<usize>>::default();
+        // 新交易最早被打包进下一个区块
+        let spend_height = self.blockchain.get_height().await + 1;
 
         // traverse utxo-*
         for result in self
@@ -43,29 +67,30 @@ impl<'a> UTXOSet {
             .database
             .read()
             .await
-            .scan_prefix(UTXO_PREFIX)
+            .scan_prefix(UTXO_PREFIX.as_bytes())
         {
-            let (key, val) = result.unwrap();
+            if accumulated >= amount {
+                break;
+            }
 
-            // decode to TxOutputs
-            let (tx_outputs, _): (TxOutputs, usize) =
+            let (key, val) = result.unwrap();
+            let (utxo, _): (Utxo, usize) =
                 bincode::decode_from_slice(&val, config::standard()).unwrap();
 
-            let tx_id = &key[UTXO_PREFIX.len()..];
-
-            // traverse TxOutput and accumulate TxOutput.amount
-            for (idx, tx_output) in tx_outputs.outputs.iter().enumerate() {
-                if accumulated < amount && tx_output.belongs_to(pub_key_hash) {
-                    spendable_outputs
-                        .entry(String::from_utf8_lossy(tx_id).to_string())
-                        .or_insert_with(Vec::new)
-                        .push(idx);
-                    accumulated += tx_output.amount;
-                }
+            // 未成熟的coinbase不可花费
+            if !utxo.output.belongs_to(pub_key_hash) || !utxo.is_mature(spend_height) {
+                continue;
             }
+
+            let (tx_id, out_idx) = UTXOSet::parse_utxo_key(&key);
+            spendable_outputs
+                .entry(tx_id)
+                .or_insert_with(Vec::new)
+                .push(out_idx);
+            accumulated += utxo.output.amount;
         }
 
-        if accumulated > amount {
+        if accumulated >= amount {
             return Some((accumulated, spendable_outputs));
         }
         None
@@ -88,21 +113,44 @@ impl<'a> UTXOSet {
             .database
             .read()
             .await
-            .scan_prefix(UTXO_PREFIX)
+            .scan_prefix(UTXO_PREFIX.as_bytes())
         {
             let (_, val) = result.unwrap();
-            let (tx_outputs, _): (TxOutputs, usize) =
-                bincode::decode_from_slice(&val, standard()).unwrap();
-            for tx_output in tx_outputs.outputs {
-                if tx_output.belongs_to(pub_key_hash) {
-                    utxos.push(tx_output);
-                }
+            let (utxo, _): (Utxo, usize) = bincode::decode_from_slice(&val, standard()).unwrap();
+            if utxo.output.belongs_to(pub_key_hash) {
+                utxos.push(utxo.output);
             }
         }
 
         utxos
     }
 
+    /// 查询某个未花费的TxOutput
+    ///
+    /// # Arguments
+    ///
+    /// - `&self` (`undefined`) - UTXO
+    /// - `tx_id` (`&[u8]`) - Output所属的Tx
+    /// - `out_idx` (`usize`) - Output下标
+    ///
+    /// # Returns
+    ///
+    /// - `Option<Utxo>` - 已花费或不存在时为None
+    pub async fn find_output(&self, tx_id: &[u8], out_idx: usize) -> Option<Utxo> {
+        let database = self.blockchain.database.read().await;
+        UTXOSet::read_output(&*database, tx_id, out_idx)
+    }
+
+    /// 从database中查询某个未花费的TxOutput, database可以是尚未提交的暂存视图
+    pub fn read_output(database: &dyn Storage, tx_id: &[u8], out_idx: usize) -> Option<Utxo> {
+        let key = UTXOSet::utxo_key(&hex::encode(tx_id), out_idx);
+        let val = database.get(key.as_bytes()).ok().flatten()?;
+        let (utxo, _): (Utxo, usize) =
+            bincode::decode_from_slice(&val, config::standard()).unwrap();
+
+        Some(utxo)
+    }
+
     /// 统计含有未花费tx的总数
     ///
     /// # Arguments
@@ -113,76 +161,147 @@ impl<'a> UTXOSet {
     ///
     /// - `u128` - 总数
     pub async fn count_tx(&self) -> u128 {
-        let mut count = 1u128;
+        let mut tx_ids = HashSet::new();
 
-        for _ in self
+        for result in self
             .blockchain
             .database
             .read()
             .await
-            .scan_prefix(UTXO_PREFIX)
+            .scan_prefix(UTXO_PREFIX.as_bytes())
         {
-            count += 1;
+            let (key, _) = result.unwrap();
+            tx_ids.insert(UTXOSet::parse_utxo_key(&key).0);
         }
 
-        count
+        tx_ids.len() as u128
     }
 
-    /// 用Block刷新现有的UTXO
+    /// 把Block对UTXO的修改写入batch, 同时记录回滚该Block所需的undo数据
+    ///
+    /// batch需与区块及主链顶端一起提交, 保证UTXO set与主链一致
     ///
     /// # Arguments
     ///
-    /// - `&self` (`undefined`) - UTXO
-    /// - `block` (`&'a Block`) - Block
-    pub async fn update(&self, block: &Block) {
-        for tx in &block.transactions {
-            let database = self.blockchain.database.write().await;
+    /// - `database` (`&dyn Storage`) - 数据库, 调用方需持有写锁
+    /// - `block` (`&Block`) - 接入主链顶端的Block
+    /// - `batch` (`&mut WriteBatch`) - 待提交的batch
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<SpentOutput>` - 该Block花费的所有Output, 按input顺序
+    pub fn connect_block(
+        database: &dyn Storage,
+        block: &Block,
+        batch: &mut WriteBatch,
+    ) -> Vec<SpentOutput> {
+        let mut undo = BlockUndo::default();
+        let mut spent = vec![];
+        // 本区块新产生的Output, 尚未写入数据库
+        let mut created = HashMap::<String, Utxo>::new();
 
-            // invalid referenced UTXO
-            for input in &tx.inputs {
-                let input_tx_id = hex::encode(&input.tx_id);
-                let key = format!("{}{}", UTXO_PREFIX, input_tx_id);
-                let val = database.get(key.clone()).ok().flatten().unwrap();
+        for tx in &block.transactions {
+            // invalid referenced UTXO, coinbase has no real input
+            for input in tx.inputs.iter().filter(|_| !tx.is_coinbase()) {
+                let key = UTXOSet::utxo_key(&hex::encode(&input.tx_id), input.out_idx);
+                if let Some(utxo) = created.remove(&key) {
+                    spent.push(SpentOutput {
+                        tx_id: input.tx_id.clone(),
+                        out_idx: input.out_idx,
+                        utxo,
+                    });
+                    continue;
+                }
 
-                let (tx_outputs, _): (TxOutputs, usize) =
+                let val = database
+                    .get(key.as_bytes())
+                    .expect("Failed to update Block.Input")
+                    .unwrap_or_else(|| panic!("Spent output {} is not in UTXO set", key));
+                let (utxo, _): (Utxo, usize) =
                     bincode::decode_from_slice(&val, config::standard()).unwrap();
+                let spent_output = SpentOutput {
+                    tx_id: input.tx_id.clone(),
+                    out_idx: input.out_idx,
+                    utxo,
+                };
+                undo.spent.push(spent_output.clone());
+                spent.push(spent_output);
+                batch.delete(key);
+            }
 
-                let mut utxos: Vec<TxOutput> = vec![];
+            // save new TxOutput, 保持原out_idx
+            let tx_id = hex::encode(&tx.id);
+            for (out_idx, output) in tx.outputs.iter().enumerate() {
+                let utxo = Utxo {
+                    output: output.clone(),
+                    height: block.height,
+                    coinbase: tx.is_coinbase(),
+                };
+                created.insert(UTXOSet::utxo_key(&tx_id, out_idx), utxo);
+            }
+        }
 
-                for (idx, tx_output) in tx_outputs.outputs.into_iter().enumerate() {
-                    if idx != input.out_idx {
-                        utxos.push(tx_output);
-                    }
-                }
+        for (key, utxo) in created {
+            let bytes = bincode::encode_to_vec(utxo, config::standard()).unwrap();
+            batch.put(key, bytes);
+        }
 
-                let unspent_tx_outputs = TxOutputs::new(utxos);
-                let bytes = bincode::encode_to_vec(unspent_tx_outputs, config::standard()).unwrap();
-                database
-                    .insert(key, bytes)
-                    .expect("Failed to update Block.Input");
-            }
+        let undo_key = format!("{}{}", UNDO_PREFIX, &block.hash);
+        let bytes = bincode::encode_to_vec(undo, config::standard()).unwrap();
+        batch.put(undo_key, bytes);
+        batch.put(UTXO_TIP_KEY, hex::decode(&block.hash).unwrap());
 
-            // save new TxOutput
+        spent
+    }
+
+    /// 按undo数据把回滚Block的修改写入batch, 用于链重组
+    ///
+    /// # Arguments
+    ///
+    /// - `database` (`&dyn Storage`) - 数据库, 调用方需持有写锁
+    /// - `block` (`&Block`) - 待回滚的Block, 必须是当前UTXO对应的链顶端
+    /// - `batch` (`&mut WriteBatch`) - 待提交的batch
+    ///
+    /// # Returns
+    ///
+    /// - `Vec<SpentOutput>` - 恢复的Output, 不含本区块内产生又被花费的Output
+    pub fn disconnect_block(
+        database: &dyn Storage,
+        block: &Block,
+        batch: &mut WriteBatch,
+    ) -> Vec<SpentOutput> {
+        // 删除该Block产生的Output
+        for tx in &block.transactions {
             let tx_id = hex::encode(&tx.id);
-            let tx_id_key = format!("{}{}", UTXO_PREFIX, tx_id);
-
-            let new_tx_outputs = TxOutputs::new(
-                tx.outputs
-                    .iter()
-                    .map(|output| {
-                        return TxOutput {
-                            pub_key_hash: output.pub_key_hash.clone(),
-                            amount: output.amount,
-                        };
-                    })
-                    .collect(),
-            );
-            let bytes = bincode::encode_to_vec(new_tx_outputs, config::standard()).unwrap();
-            database
-                .insert(tx_id_key, bytes)
-                .expect(&format!("Failed to update Block.Output"));
+            for out_idx in 0..tx.outputs.len() {
+                batch.delete(UTXOSet::utxo_key(&tx_id, out_idx));
+            }
+        }
+
+        // 恢复被该Block花费的Output
+        let undo_key = format!("{}{}", UNDO_PREFIX, &block.hash);
+        let val = database
+            .get(undo_key.as_bytes())
+            .expect("Failed to disconnect Block.Input")
+            .unwrap_or_else(|| panic!("Missing undo data of block {}", &block.hash));
+        let (undo, _): (BlockUndo, usize) =
+            bincode::decode_from_slice(&val, config::standard()).unwrap();
+
+        for spent in &undo.spent {
+            let key = UTXOSet::utxo_key(&hex::encode(&spent.tx_id), spent.out_idx);
+            let bytes = bincode::encode_to_vec(&spent.utxo, config::standard()).unwrap();
+            batch.put(key, bytes);
         }
-        // todo!("这里的tx output idx 协变了");
+
+        batch.delete(undo_key);
+        batch.put(UTXO_TIP_KEY, hex::decode(&block.header.prev_hash).unwrap());
+
+        undo.spent
+    }
+
+    /// UTXO set当前对应的区块hash, 与主链顶端不一致说明上次写入被中断
+    pub fn tip(database: &dyn Storage) -> Option<Vec<u8>> {
+        database.get(UTXO_TIP_KEY.as_bytes()).ok().flatten()
     }
 
     /// 重建utxo set的数据库
@@ -191,31 +310,45 @@ impl<'a> UTXOSet {
     ///
     /// - `&self` (`undefined`) - UTXOSet
     pub async fn rebuild(&self) {
-        self.clear_utxo().await;
-        let utxos = self.blockchain.find_utxos().await;
-        if utxos.is_empty() {
-            return;
+        UTXOSet::reindex(&self.blockchain).await;
+    }
+
+    /// 清空UTXO set后从创世块开始依次重放主链
+    ///
+    /// # Arguments
+    ///
+    /// - `blockchain` (`&Blockchain`) - 主链
+    pub async fn reindex(blockchain: &Blockchain) {
+        UTXOSet::clear_utxo(blockchain).await;
+
+        // iterator从链顶向前遍历, 需要反转为从创世块开始
+        let mut blocks = vec![];
+        let mut iter = blockchain.iterator().await;
+        while let Some(block) = iter.next().await {
+            blocks.push(block);
         }
 
-        let database = self.blockchain.database.write().await;
-        for (k, v) in utxos.into_iter() {
-            let utxo_key = format!("{}{}", UTXO_PREFIX, k);
-            let utxo_bytes = bincode::encode_to_vec(v, config::standard()).unwrap();
+        let database = blockchain.database.write().await;
+        for block in blocks.iter().rev() {
+            let mut batch = WriteBatch::default();
+            UTXOSet::connect_block(&*database, block, &mut batch);
             database
-                .insert(utxo_key.clone(), utxo_bytes)
-                .expect(&format!(
-                    "Failed to rebuild utxo set, insert {} tx error",
-                    utxo_key
-                ));
+                .apply_batch(batch)
+                .unwrap_or_else(|_| panic!("Failed to rebuild utxo set at block {}", &block.hash));
         }
     }
 
-    /// 删除blockchain存储的所有utxo-记录
-    async fn clear_utxo(&self) {
-        let database = self.blockchain.database.read().await;
-        for result in database.scan_prefix(UTXO_PREFIX) {
-            let (k, _) = result.unwrap();
-            database.remove(k).expect("Failed to clear UTXO set!");
+    /// 删除blockchain存储的所有utxo-与undo-记录
+    async fn clear_utxo(blockchain: &Blockchain) {
+        let database = blockchain.database.write().await;
+        database
+            .delete(UTXO_TIP_KEY.as_bytes())
+            .expect("Failed to clear UTXO set!");
+        for prefix in [UTXO_PREFIX, UNDO_PREFIX] {
+            for result in database.scan_prefix(prefix.as_bytes()) {
+                let (k, _) = result.unwrap();
+                database.delete(&k).expect("Failed to clear UTXO set!");
+            }
         }
     }
 }
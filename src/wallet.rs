@@ -44,6 +44,13 @@ impl Wallet {
      */
     pub fn address(&self) -> String {
         let pub_key_hashed = Wallet::hash_pub_key(&self.pub_key);
+        Wallet::pub_key_hash_to_address(&pub_key_hashed)
+    }
+
+    /*
+     * 由pub_key哈希还原Address
+     */
+    pub fn pub_key_hash_to_address(pub_key_hashed: &[u8]) -> String {
         let mut ver_pubkey = vec![VERSION];
         // 追加pub_key哈希
         ver_pubkey.extend(pub_key_hashed);